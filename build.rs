@@ -3,8 +3,8 @@ use std::{env, fs};
 
 fn main() {
   load_env();
-  load_lib("ffmpeg", &["avcodec", "avformat", "avutil", "swscale"]);
-  load_lib("libwebp", &["libwebp"]);
+  load_lib("ffmpeg", &["avcodec", "avformat", "avutil", "swresample", "swscale"]);
+  load_lib("libwebp", &["libwebp", "libwebpmux"]);
 }
 
 fn load_lib(name: &str, libs: &[&str]) {
@@ -0,0 +1,268 @@
+use crate::ffmpeg;
+use crate::math;
+use crate::rumpeg::*;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Rate audio is resampled to before rendering, which keeps everything up to 8 kHz
+const RENDER_SAMPLE_RATE: i32 = 16000;
+const FFT_SIZE: usize = 1024;
+/// Spectrogram floor, quieter frequencies are drawn as background
+const MIN_DB: f32 = -90.;
+const DEFAULT_WIDTH: i32 = 1024;
+const DEFAULT_HEIGHT: i32 = 256;
+
+#[derive(Debug)]
+pub struct Audio {
+  pub channels: i32,
+  pub duration_ms: i64,
+  pub sample_rate: i32,
+  codec_context: AVCodecContext,
+  format_context: AVFormatContext,
+  swr_context: SwrContext,
+}
+
+#[derive(Error, Debug)]
+pub enum AudioError {
+  #[error(transparent)]
+  Rumpeg(#[from] RumpegError),
+  #[error("Could not determine the duration of the audio stream")]
+  DurationMissing,
+  #[error("Unknown audio image mode \"{0}\"")]
+  UnknownMode(String),
+  #[error("Invalid color \"{0}\", expected rrggbb")]
+  InvalidColor(String),
+}
+
+type AudioResult<T = ()> = Result<T, AudioError>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AudioImageMode {
+  /// Peak amplitude over time
+  #[default]
+  Waveform,
+  /// Frequency content over time, low frequencies at the bottom
+  Spectrogram,
+}
+
+impl FromStr for AudioImageMode {
+  type Err = AudioError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "waveform" => Ok(Self::Waveform),
+      "spectrogram" => Ok(Self::Spectrogram),
+      s => Err(AudioError::UnknownMode(s.to_string())),
+    }
+  }
+}
+
+/// A color written as `rrggbb`, with or without a leading `#`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgb(pub [u8; 3]);
+
+impl Rgb {
+  fn lerp(self, other: Self, t: f32) -> Self {
+    let mut color = self.0;
+    for (c, o) in color.iter_mut().zip(other.0) {
+      *c = (*c as f32 + (o as f32 - *c as f32) * t) as u8;
+    }
+    Self(color)
+  }
+}
+
+impl fmt::Display for Rgb {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let [r, g, b] = self.0;
+    write!(f, "{r:02x}{g:02x}{b:02x}")
+  }
+}
+
+impl FromStr for Rgb {
+  type Err = AudioError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || AudioError::InvalidColor(s.to_string());
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 {
+      return Err(invalid());
+    }
+    let channel = |i: usize| {
+      hex
+        .get(i..i + 2)
+        .and_then(|c| u8::from_str_radix(c, 16).ok())
+        .ok_or_else(invalid)
+    };
+    Ok(Self([channel(0)?, channel(2)?, channel(4)?]))
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioImageOptions {
+  pub mode: AudioImageMode,
+  pub width: i32,
+  pub height: i32,
+  pub foreground: Rgb,
+  pub background: Rgb,
+}
+
+impl Default for AudioImageOptions {
+  fn default() -> Self {
+    Self {
+      mode: AudioImageMode::default(),
+      width: DEFAULT_WIDTH,
+      height: DEFAULT_HEIGHT,
+      foreground: Rgb([75, 205, 94]),
+      background: Rgb([24, 24, 24]),
+    }
+  }
+}
+
+impl AudioImageOptions {
+  /// Size of the image, a missing dimension is left to its default
+  fn size(&self) -> (i32, i32) {
+    (
+      if self.width > 0 {
+        self.width
+      } else {
+        DEFAULT_WIDTH
+      },
+      if self.height > 0 {
+        self.height
+      } else {
+        DEFAULT_HEIGHT
+      },
+    )
+  }
+
+  /// Options with the defaults filled in, in a fixed order
+  pub fn cache_key(&self) -> String {
+    let (width, height) = self.size();
+    format!(
+      "mode={:?} width={width} height={height} color={} background={}",
+      self.mode, self.foreground, self.background
+    )
+  }
+}
+
+impl Audio {
+  pub fn open(filepath: &str) -> AudioResult<Self> {
+    let format_context = AVFormatContext::new_audio(filepath)?;
+    let codec_context = AVCodecContext::new(&format_context.stream, &DecoderOptions::default())?;
+    let swr_context = SwrContext::new(&codec_context, RENDER_SAMPLE_RATE)?;
+
+    // Not every container stores the duration per stream
+    let duration_ms = match format_context.stream.duration {
+      d if d > 0 => format_context.stream.duration_millis(),
+      _ => format_context.duration / (ffmpeg::AV_TIME_BASE as i64 / 1000),
+    };
+
+    Ok(Self {
+      channels: codec_context.ch_layout.nb_channels,
+      duration_ms,
+      sample_rate: codec_context.sample_rate,
+      codec_context,
+      format_context,
+      swr_context,
+    })
+  }
+
+  /// Decodes the whole stream and draws it as an RGB image
+  pub fn render(&self, options: AudioImageOptions) -> AudioResult<AVFrame> {
+    if self.duration_ms <= 0 {
+      return Err(AudioError::DurationMissing);
+    }
+
+    let (width, height) = options.size();
+
+    let expected_samples = std::cmp::max(1, self.duration_ms * RENDER_SAMPLE_RATE as i64 / 1000);
+    // The duration is only an estimate, samples past it end up in the last column
+    let column_of = |sample: i64| {
+      std::cmp::min(sample * width as i64 / expected_samples, width as i64 - 1) as usize
+    };
+
+    let mut frame = AVFrame::new(ffmpeg::AVPixelFormat_AV_PIX_FMT_RGB24, width, height)?;
+    let stride = frame.linesize[0] as usize;
+    let pixels = frame.data_mut(0);
+    for row in pixels.chunks_mut(stride) {
+      for pixel in row[..width as usize * 3].chunks_mut(3) {
+        pixel.copy_from_slice(&options.background.0);
+      }
+    }
+
+    self.codec_context.flush();
+    let samples = self
+      .format_context
+      .samples(self.codec_context.as_ptr(), &self.swr_context)
+      .flatten();
+
+    match options.mode {
+      AudioImageMode::Waveform => {
+        let mut peaks = vec![(0_f32, 0_f32); width as usize];
+        for (i, sample) in samples.enumerate() {
+          let (min, max) = &mut peaks[column_of(i as i64)];
+          *min = min.min(sample);
+          *max = max.max(sample);
+        }
+
+        let to_row =
+          |amplitude: f32| ((1. - amplitude.clamp(-1., 1.)) * (height - 1) as f32 / 2.) as usize;
+        for (x, (min, max)) in peaks.into_iter().enumerate() {
+          for y in to_row(max)..=to_row(min) {
+            let offset = y * stride + x * 3;
+            pixels[offset..offset + 3].copy_from_slice(&options.foreground.0);
+          }
+        }
+      }
+      AudioImageMode::Spectrogram => {
+        let window: Vec<f32> = (0..FFT_SIZE)
+          .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos())
+          .collect();
+        let mut columns = vec![Vec::new(); width as usize];
+        let mut buffer = Vec::with_capacity(FFT_SIZE);
+        let mut current = 0;
+
+        let spectrum = |buffer: &mut Vec<f32>| {
+          let mut re = vec![0_f32; FFT_SIZE];
+          let mut im = vec![0_f32; FFT_SIZE];
+          for (i, sample) in buffer.drain(..).enumerate() {
+            re[i] = sample * window[i];
+          }
+          math::fft(&mut re, &mut im);
+
+          // Rows map linearly onto the lower half of the bins
+          (0..height as usize)
+            .map(|y| {
+              let bin = (height as usize - 1 - y) * (FFT_SIZE / 2) / height as usize;
+              let magnitude = f32::hypot(re[bin], im[bin]) / (FFT_SIZE / 2) as f32;
+              let db = 20. * (magnitude + f32::EPSILON).log10();
+              ((db - MIN_DB) / -MIN_DB).clamp(0., 1.)
+            })
+            .collect::<Vec<_>>()
+        };
+
+        for (i, sample) in samples.enumerate() {
+          let column = column_of(i as i64);
+          if column != current {
+            columns[current] = spectrum(&mut buffer);
+            current = column;
+          }
+          // Only the start of each column is analyzed
+          if buffer.len() < FFT_SIZE {
+            buffer.push(sample);
+          }
+        }
+        columns[current] = spectrum(&mut buffer);
+
+        for (x, column) in columns.into_iter().enumerate() {
+          for (y, intensity) in column.into_iter().enumerate() {
+            let offset = y * stride + x * 3;
+            let color = options.background.lerp(options.foreground, intensity);
+            pixels[offset..offset + 3].copy_from_slice(&color.0);
+          }
+        }
+      }
+    }
+
+    Ok(frame)
+  }
+}
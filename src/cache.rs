@@ -0,0 +1,544 @@
+use crate::ascii::LogDisplay;
+use crate::http::fnv1a;
+use crate::log;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const ENTRY_EXTENSION: &str = "thumb";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Makes the temporary file of every write unique, even for writes of the same key
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Content-addressed directory of rendered images, evicting the least recently
+/// used entries once it grows past its size cap
+///
+/// Entries are keyed by everything that affects the rendered image, including the
+/// identity of the source file. Once a source changes, the entries rendered from its
+/// previous identity are removed the next time it is looked up
+#[derive(Debug)]
+pub struct ThumbnailCache {
+  dir: PathBuf,
+  max_size: u64,
+  index: Mutex<CacheIndex>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+/// Identifies a rendered image, files are named after the hash of the whole key and
+/// store the key itself to rule out collisions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+  source: String,
+  identity: String,
+  variant: String,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+  entries: HashMap<u64, CacheEntry>,
+  /// Hashes of the entries rendered from each source file
+  sources: HashMap<String, HashSet<u64>>,
+  size: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+  size: u64,
+  last_used: SystemTime,
+  source: String,
+  identity: String,
+}
+
+/// A cached image along with the response headers it was served with
+#[derive(Debug)]
+pub struct CachedImage {
+  pub headers: Vec<(String, String)>,
+  pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  pub entries: usize,
+  pub size: u64,
+  pub max_size: u64,
+}
+
+impl CacheKey {
+  /// `identity` changes whenever the source file does, `variant` describes everything
+  /// else that changes the image
+  pub fn new(source: &str, identity: &str, variant: &str) -> Self {
+    Self {
+      source: source.to_string(),
+      identity: identity.to_string(),
+      variant: variant.to_string(),
+    }
+  }
+
+  /// Paths cannot hold NUL bytes, so the parts can be split apart again
+  fn to_bytes(&self) -> Vec<u8> {
+    [&self.source, &self.identity, &self.variant]
+      .map(String::as_str)
+      .join("\0")
+      .into_bytes()
+  }
+
+  fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    let key = std::str::from_utf8(bytes).ok()?;
+    let mut parts = key.splitn(3, '\0');
+    Some(Self::new(parts.next()?, parts.next()?, parts.next()?))
+  }
+
+  fn hash(&self) -> u64 {
+    fnv1a(&self.to_bytes())
+  }
+}
+
+impl CacheIndex {
+  fn add(&mut self, hash: u64, entry: CacheEntry) {
+    self.remove(hash);
+    self.size += entry.size;
+    self
+      .sources
+      .entry(entry.source.clone())
+      .or_default()
+      .insert(hash);
+    self.entries.insert(hash, entry);
+  }
+
+  fn remove(&mut self, hash: u64) -> Option<CacheEntry> {
+    let entry = self.entries.remove(&hash)?;
+    self.size -= entry.size;
+    if let Some(hashes) = self.sources.get_mut(&entry.source) {
+      hashes.remove(&hash);
+      if hashes.is_empty() {
+        self.sources.remove(&entry.source);
+      }
+    }
+    Some(entry)
+  }
+
+  /// Takes out the entries rendered from an earlier identity of the source of `key`
+  fn remove_stale(&mut self, key: &CacheKey) -> Vec<u64> {
+    let Some(hashes) = self.sources.get(&key.source) else {
+      return Vec::new();
+    };
+    let stale: Vec<u64> = hashes
+      .iter()
+      .filter(|hash| {
+        self
+          .entries
+          .get(hash)
+          .is_some_and(|entry| entry.identity != key.identity)
+      })
+      .copied()
+      .collect();
+    for hash in &stale {
+      self.remove(*hash);
+    }
+    stale
+  }
+}
+
+impl ThumbnailCache {
+  /// Opens the cache at `dir`, indexing the entries left by previous runs
+  pub fn open(dir: &str, max_size: u64) -> io::Result<Self> {
+    fs::create_dir_all(dir)?;
+    let mut index = CacheIndex::default();
+
+    for file in fs::read_dir(dir)? {
+      let path = file?.path();
+      let hash = path
+        .extension()
+        .filter(|ext| *ext == ENTRY_EXTENSION)
+        .and_then(|_| path.file_stem()?.to_str())
+        .and_then(|stem| u64::from_str_radix(stem, 16).ok());
+
+      match (hash, fs::metadata(&path)) {
+        (Some(hash), Ok(metadata)) if metadata.is_file() => match read_key(&path) {
+          Ok(key) if key.hash() == hash => index.add(
+            hash,
+            CacheEntry {
+              size: metadata.len(),
+              last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+              source: key.source,
+              identity: key.identity,
+            },
+          ),
+          // Written by an older version, or not an entry at all
+          _ => {
+            fs::remove_file(&path).ok();
+          }
+        },
+        // Leftovers from interrupted writes, anything else in the directory is not ours
+        _ if is_temp_file(&path) => {
+          fs::remove_file(&path).ok();
+        }
+        _ => {}
+      }
+    }
+
+    let cache = Self {
+      dir: PathBuf::from(dir),
+      max_size,
+      index: Mutex::new(index),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+    };
+    cache.evict(None);
+
+    Ok(cache)
+  }
+
+  pub fn get(&self, key: &CacheKey) -> Option<CachedImage> {
+    self.remove_stale(key);
+    let image = self.read(key);
+    let counter = if image.is_some() {
+      &self.hits
+    } else {
+      &self.misses
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    image
+  }
+
+  pub fn insert(&self, key: &CacheKey, image: &CachedImage) -> io::Result<()> {
+    let key_bytes = key.to_bytes();
+    let mut content = Vec::with_capacity(key_bytes.len() + image.data.len() + 256);
+    content.extend_from_slice(format!("{}\n", key_bytes.len()).as_bytes());
+    content.extend_from_slice(&key_bytes);
+    content.push(b'\n');
+    for (name, value) in &image.headers {
+      content.extend_from_slice(format!("{name}: {value}\n").as_bytes());
+    }
+    content.push(b'\n');
+    content.extend_from_slice(&image.data);
+
+    // Written to a temporary file first so readers never see a partial entry
+    let hash = key.hash();
+    let temp_path = self.dir.join(format!(
+      "{hash:016x}-{}-{}.{TEMP_EXTENSION}",
+      std::process::id(),
+      TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = File::create(&temp_path)
+      .and_then(|mut file| file.write_all(&content))
+      .and_then(|_| fs::rename(&temp_path, self.entry_path(hash)));
+    if let Err(e) = written {
+      fs::remove_file(&temp_path).ok();
+      return Err(e);
+    }
+
+    if let Ok(mut index) = self.index.lock() {
+      index.add(
+        hash,
+        CacheEntry {
+          size: content.len() as u64,
+          last_used: SystemTime::now(),
+          source: key.source.clone(),
+          identity: key.identity.clone(),
+        },
+      );
+    }
+
+    self.remove_stale(key);
+    self.evict(Some(hash));
+    Ok(())
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    let (entries, size) = self
+      .index
+      .lock()
+      .map(|index| (index.entries.len(), index.size))
+      .unwrap_or_default();
+
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      entries,
+      size,
+      max_size: self.max_size,
+    }
+  }
+
+  fn read(&self, key: &CacheKey) -> Option<CachedImage> {
+    // The index is only held for the lookup, so hits do not wait on each other's disk reads
+    let hash = key.hash();
+    let now = SystemTime::now();
+    self.index.lock().ok()?.entries.get_mut(&hash)?.last_used = now;
+    let path = self.entry_path(hash);
+
+    let Ok(content) = fs::read(&path) else {
+      if let Ok(mut index) = self.index.lock() {
+        index.remove(hash);
+      }
+      return None;
+    };
+
+    let (stored_key, mut rest) = split_key(&content)?;
+    // Another key with the same hash, the entry stays for whichever key wrote it
+    if stored_key != *key {
+      return None;
+    }
+
+    // The modification time doubles as the last use time across restarts
+    if let Ok(file) = File::options().write(true).open(&path) {
+      file.set_modified(now).ok();
+    }
+
+    let mut headers = Vec::new();
+    loop {
+      let end = rest.iter().position(|b| *b == b'\n')?;
+      let line = String::from_utf8_lossy(&rest[..end]);
+      rest = &rest[end + 1..];
+      let Some((name, value)) = line.split_once(": ") else {
+        break;
+      };
+      headers.push((name.to_string(), value.to_string()));
+    }
+
+    Some(CachedImage {
+      headers,
+      data: rest.to_vec(),
+    })
+  }
+
+  /// Deletes the entries rendered from an earlier identity of the source of `key`
+  fn remove_stale(&self, key: &CacheKey) {
+    let Ok(mut index) = self.index.lock() else {
+      return;
+    };
+    for hash in index.remove_stale(key) {
+      if let Err(e) = fs::remove_file(self.entry_path(hash)) {
+        log!(warn@"Could not remove stale cache entry {hash:016x}: {e}");
+      }
+    }
+  }
+
+  /// Removes the least recently used entries until the cache fits its size cap,
+  /// `keep` is never evicted
+  fn evict(&self, keep: Option<u64>) {
+    let Ok(mut index) = self.index.lock() else {
+      return;
+    };
+
+    while index.size > self.max_size {
+      let Some(hash) = index
+        .entries
+        .iter()
+        .filter(|(hash, _)| Some(**hash) != keep)
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(hash, _)| *hash)
+      else {
+        break;
+      };
+
+      index.remove(hash);
+      if let Err(e) = fs::remove_file(self.entry_path(hash)) {
+        log!(warn@"Could not evict cache entry {hash:016x}: {e}");
+      }
+    }
+  }
+
+  fn entry_path(&self, hash: u64) -> PathBuf {
+    self.dir.join(format!("{hash:016x}.{ENTRY_EXTENSION}"))
+  }
+}
+
+/// Splits an entry into its key and the rest, the key is prefixed by its length
+fn split_key(content: &[u8]) -> Option<(CacheKey, &[u8])> {
+  let end = content.iter().position(|b| *b == b'\n')?;
+  let len: usize = std::str::from_utf8(&content[..end]).ok()?.parse().ok()?;
+  let key = content.get(end + 1..end + 1 + len)?;
+  let rest = content.get(end + 1 + len..)?.strip_prefix(b"\n")?;
+  Some((CacheKey::from_bytes(key)?, rest))
+}
+
+/// Reads only the key of the entry at `path`, to index it without loading the image
+fn read_key(path: &Path) -> io::Result<CacheKey> {
+  let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid cache entry");
+  let mut reader = BufReader::new(File::open(path)?);
+  let mut line = Vec::new();
+  reader.read_until(b'\n', &mut line)?;
+  let len: u64 = std::str::from_utf8(&line)
+    .ok()
+    .and_then(|len| len.trim_end().parse().ok())
+    .ok_or_else(invalid)?;
+  let mut key = Vec::new();
+  reader.take(len).read_to_end(&mut key)?;
+  CacheKey::from_bytes(&key).ok_or_else(invalid)
+}
+
+/// Whether `path` is named like the temporary files of [`ThumbnailCache::insert`]
+fn is_temp_file(path: &Path) -> bool {
+  let is_temp = path.extension().is_some_and(|ext| ext == TEMP_EXTENSION);
+  let stem = path
+    .file_stem()
+    .and_then(|stem| stem.to_str())
+    .unwrap_or_default();
+  let mut parts = stem.split('-');
+  is_temp
+    && parts
+      .next()
+      .is_some_and(|key| key.len() == 16 && u64::from_str_radix(key, 16).is_ok())
+    && parts.all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Cache directory removed on drop
+  struct TempCache(PathBuf);
+
+  impl TempCache {
+    fn new(name: &str) -> Self {
+      Self(std::env::temp_dir().join(format!("cache-{name}-{}", std::process::id())))
+    }
+
+    fn open(&self, max_size: u64) -> ThumbnailCache {
+      ThumbnailCache::open(self.0.to_str().unwrap(), max_size).unwrap()
+    }
+
+    fn files(&self) -> usize {
+      fs::read_dir(&self.0).unwrap().count()
+    }
+  }
+
+  impl Drop for TempCache {
+    fn drop(&mut self) {
+      fs::remove_dir_all(&self.0).ok();
+    }
+  }
+
+  fn image(data: &str) -> CachedImage {
+    CachedImage {
+      headers: vec![("Content-Type".to_string(), "image/webp".to_string())],
+      data: data.as_bytes().to_vec(),
+    }
+  }
+
+  fn key(source: &str, variant: &str) -> CacheKey {
+    CacheKey::new(source, "1", variant)
+  }
+
+  /// Size of an entry holding `image(data)` under a key of `key_len` bytes
+  fn entry_size(key_len: usize, data: &str) -> u64 {
+    let header = "Content-Type: image/webp\n\n";
+    (key_len.to_string().len() + 1 + key_len + 1 + header.len() + data.len()) as u64
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let dir = TempCache::new("lru");
+    let data = "x".repeat(100);
+    let size = entry_size(key("a", "v").to_bytes().len(), &data);
+    let cache = dir.open(size * 2);
+
+    cache.insert(&key("a", "v"), &image(&data)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    cache.insert(&key("b", "v"), &image(&data)).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert!(cache.get(&key("a", "v")).is_some());
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    cache.insert(&key("c", "v"), &image(&data)).unwrap();
+
+    assert!(cache.get(&key("a", "v")).is_some());
+    assert!(cache.get(&key("b", "v")).is_none());
+    assert!(cache.get(&key("c", "v")).is_some());
+  }
+
+  #[test]
+  fn stays_under_size_cap() {
+    let dir = TempCache::new("cap");
+    let cache = dir.open(1000);
+
+    for i in 0..20 {
+      cache
+        .insert(&key(&format!("{i}"), "v"), &image(&"x".repeat(90)))
+        .unwrap();
+      assert!(cache.stats().size <= 1000);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.entries, dir.files());
+    assert!(stats.entries < 20);
+
+    // The entry just written is kept even when it alone is over the cap
+    cache
+      .insert(&key("big", "v"), &image(&"x".repeat(2000)))
+      .unwrap();
+    assert_eq!(cache.stats().entries, 1);
+    assert!(cache.get(&key("big", "v")).is_some());
+  }
+
+  #[test]
+  fn rebuilds_index_on_open() {
+    let dir = TempCache::new("reopen");
+    let before = {
+      let cache = dir.open(u64::MAX);
+      cache.insert(&key("a", "v"), &image("first")).unwrap();
+      cache.insert(&key("b", "v"), &image("second")).unwrap();
+      cache.stats()
+    };
+    fs::write(dir.0.join("0123456789abcdef-1-2.tmp"), "partial").unwrap();
+    fs::write(dir.0.join("notes.txt"), "not ours").unwrap();
+
+    let cache = dir.open(u64::MAX);
+    let after = cache.stats();
+    assert_eq!(after.entries, before.entries);
+    assert_eq!(after.size, before.size);
+    assert!(!dir.0.join("0123456789abcdef-1-2.tmp").exists());
+    assert!(dir.0.join("notes.txt").exists());
+
+    let image = cache.get(&key("a", "v")).unwrap();
+    assert_eq!(image.data, b"first");
+    assert_eq!(
+      image.headers,
+      [("Content-Type".to_string(), "image/webp".to_string())]
+    );
+  }
+
+  #[test]
+  fn removes_entries_of_modified_source() {
+    let dir = TempCache::new("modified");
+    let cache = dir.open(u64::MAX);
+    cache.insert(&key("a", "v1"), &image("a1")).unwrap();
+    cache.insert(&key("a", "v2"), &image("a2")).unwrap();
+    cache.insert(&key("b", "v1"), &image("b1")).unwrap();
+
+    assert!(cache.get(&CacheKey::new("a", "2", "v1")).is_none());
+    assert_eq!(cache.stats().entries, 1);
+    assert_eq!(dir.files(), 1);
+    assert!(cache.get(&key("b", "v1")).is_some());
+  }
+
+  #[test]
+  fn ignores_entries_of_colliding_keys() {
+    let dir = TempCache::new("collision");
+    let cache = dir.open(u64::MAX);
+    let (stored, other) = (key("a", "v"), key("b", "v"));
+    cache.insert(&stored, &image("a")).unwrap();
+
+    // Moves the entry to where `other` is looked up, as if both keys had the same hash
+    fs::rename(
+      cache.entry_path(stored.hash()),
+      cache.entry_path(other.hash()),
+    )
+    .unwrap();
+    let mut index = cache.index.lock().unwrap();
+    let entry = index.remove(stored.hash()).unwrap();
+    index.add(other.hash(), entry);
+    drop(index);
+
+    assert!(cache.get(&other).is_none());
+    assert_eq!(cache.stats().entries, 1);
+  }
+}
@@ -1,20 +1,56 @@
 use std::{env, str::FromStr};
 use thiserror::Error;
 
-use crate::rumpeg::{LogLevel, SeekPosition};
+use crate::audio::AudioImageMode;
+use crate::http::SymlinkPolicy;
+use crate::rumpeg::{DecoderOptions, LogLevel, SeekMode, SeekPosition, StreamSelector};
+use crate::video::PreviewOptions;
+use crate::webp::WebPOptions;
+
+/// Media files are revalidated on every request, which is cheap now that they have validators
+const DEFAULT_MEDIA_CACHE_CONTROL: &str = "no-cache";
+const DEFAULT_FRAME_CACHE_CONTROL: &str = "public, max-age=3600";
+const DEFAULT_CACHE_DIR: &str = "temp/cache";
+/// Its extension selects the image format, see [`crate::image::ImageFormat`]
+const DEFAULT_OUTPUT: &str = "temp/image.webp";
+/// Size cap of the thumbnail cache in MiB
+const DEFAULT_CACHE_SIZE_MB: u64 = 256;
+/// Open videos kept around for reuse, each holds a file descriptor and decoder state
+const DEFAULT_VIDEO_POOL_SIZE: usize = 16;
+const DEFAULT_VIDEO_IDLE_SECS: u64 = 60;
 
 #[derive(Debug)]
 pub struct CLIArgs {
   pub host: bool,
   pub film: bool,
+  pub preview: bool,
   pub debug: bool,
+  pub json: bool,
+  pub waveform: bool,
+  pub audio_image_mode: AudioImageMode,
   pub filepath: String,
+  pub output: String,
   pub height: i32,
   pub seek_position: SeekPosition,
   pub width: i32,
   pub log_level: LogLevel,
   pub end: SeekPosition,
   pub step: SeekPosition,
+  pub stream: StreamSelector,
+  pub decoder: DecoderOptions,
+  pub seek_mode: SeekMode,
+  pub workers: usize,
+  pub queue_size: usize,
+  pub symlink_policy: SymlinkPolicy,
+  pub media_cache_control: String,
+  pub frame_cache_control: String,
+  pub no_cache: bool,
+  pub cache_dir: String,
+  pub cache_size: u64,
+  pub video_pool_size: usize,
+  pub video_idle_timeout: u64,
+  pub webp: WebPOptions,
+  pub preview_options: PreviewOptions,
 }
 
 impl CLIArgs {
@@ -23,8 +59,16 @@ impl CLIArgs {
     Ok(Self {
       host: Self::find_flag(&args, "-host"),
       film: Self::find_flag(&args, "-f"),
+      preview: Self::find_flag(&args, "-preview"),
       debug: Self::find_flag(&args, "-d"),
+      json: Self::find_flag(&args, "-json"),
+      waveform: Self::find_flag(&args, "-waveform"),
+      audio_image_mode: Self::find_arg(&args, "-mode"),
       filepath: args.get(1).ok_or(CLIError::FilepathMissing)?.clone(),
+      output: match Self::find_arg::<String>(&args, "-o") {
+        s if s.is_empty() => DEFAULT_OUTPUT.to_string(),
+        s => s,
+      },
       height: Self::find_arg(&args, "-h"),
       seek_position: Self::find_arg(&args, "-s"),
       width: Self::find_arg(&args, "-w"),
@@ -37,20 +81,89 @@ impl CLIArgs {
         SeekPosition::TimeBase(0) => SeekPosition::TimeBase(1),
         n => n,
       },
+      stream: Self::find_arg(&args, "-stream"),
+      decoder: DecoderOptions {
+        thread_count: Self::find_arg(&args, "-threads"),
+        thread_type: Self::find_arg(&args, "-thread-type"),
+        skip_loop_filter: Self::find_arg(&args, "-skip-loop-filter"),
+        skip_idct: Self::find_arg(&args, "-skip-idct"),
+        lowres: Self::find_arg(&args, "-lowres"),
+        skip_frame: Self::find_arg(&args, "-skip-frame"),
+      },
+      seek_mode: if Self::find_flag(&args, "-approximate") {
+        SeekMode::Keyframe
+      } else {
+        SeekMode::Accurate
+      },
+      workers: Self::find_arg(&args, "-workers"),
+      queue_size: Self::find_arg(&args, "-queue"),
+      symlink_policy: Self::find_arg(&args, "-symlinks"),
+      media_cache_control: match Self::find_arg::<String>(&args, "-media-cache") {
+        s if s.is_empty() => DEFAULT_MEDIA_CACHE_CONTROL.to_string(),
+        s => s,
+      },
+      frame_cache_control: match Self::find_arg::<String>(&args, "-frame-cache") {
+        s if s.is_empty() => DEFAULT_FRAME_CACHE_CONTROL.to_string(),
+        s => s,
+      },
+      no_cache: Self::find_flag(&args, "-no-cache"),
+      cache_dir: match Self::find_arg::<String>(&args, "-cache-dir") {
+        s if s.is_empty() => DEFAULT_CACHE_DIR.to_string(),
+        s => s,
+      },
+      cache_size: match Self::find_arg(&args, "-cache-size") {
+        0 => DEFAULT_CACHE_SIZE_MB,
+        n => n,
+      },
+      video_pool_size: match Self::find_arg(&args, "-video-pool") {
+        0 => DEFAULT_VIDEO_POOL_SIZE,
+        n => n,
+      },
+      video_idle_timeout: match Self::find_arg(&args, "-video-idle") {
+        0 => DEFAULT_VIDEO_IDLE_SECS,
+        n => n,
+      },
+      webp: Self::webp_options(&args),
+      preview_options: PreviewOptions {
+        fps: Self::find_arg(&args, "-fps"),
+        loop_count: Self::find_arg(&args, "-loop"),
+        max_frames: Self::find_arg_or(&args, "-frames", PreviewOptions::default().max_frames),
+      },
     })
   }
 
+  fn webp_options(args: &[String]) -> WebPOptions {
+    let defaults = WebPOptions::default();
+    WebPOptions {
+      quality: Self::find_arg_or(args, "-q", defaults.quality),
+      lossless: Self::find_flag(args, "-lossless"),
+      near_lossless: Self::find_arg_or(args, "-near-lossless", defaults.near_lossless),
+      method: Self::find_arg_or(args, "-method", defaults.method),
+      preset: Self::find_arg(args, "-preset"),
+      target_size: Self::find_arg(args, "-target-size"),
+      target_psnr: Self::find_arg(args, "-target-psnr"),
+      alpha_quality: Self::find_arg_or(args, "-alpha-q", defaults.alpha_quality),
+      sharp_yuv: Self::find_flag(args, "-sharp-yuv"),
+      multithreading: Self::find_flag(args, "-mt"),
+    }
+  }
+
   fn find_flag(args: &[String], arg_name: &str) -> bool {
     args.iter().any(|arg| arg == arg_name)
   }
 
   fn find_arg<F: FromStr + Default>(args: &[String], arg_name: &str) -> F {
+    Self::find_arg_or(args, arg_name, F::default())
+  }
+
+  /// Like [`Self::find_arg`], for values where the type's default is not a sensible one
+  fn find_arg_or<F: FromStr>(args: &[String], arg_name: &str, default: F) -> F {
     args
       .iter()
       .position(|arg| arg == arg_name)
       .and_then(|i| args.get(i + 1))
       .and_then(|n| n.parse::<F>().ok())
-      .unwrap_or_default()
+      .unwrap_or(default)
   }
 }
 
@@ -53,4 +53,7 @@ pub const AVERROR_HTTP_NOT_FOUND: i32 = FFERRTAG(0xF8, b'4', b'0', b'4');
 pub const AVERROR_HTTP_OTHER_4XX: i32 = FFERRTAG(0xF8, b'4', b'X', b'X');
 pub const AVERROR_HTTP_SERVER_ERROR: i32 = FFERRTAG(0xF8, b'5', b'X', b'X');
 
+// timestamps, bindgen can't evaluate the cast in the C macro
+pub const AV_NOPTS_VALUE: i64 = i64::MIN;
+
 include!(concat!(env!("OUT_DIR"), "/ffmpeg.rs"));
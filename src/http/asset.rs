@@ -1,19 +1,26 @@
 use std::{
   collections::HashMap,
-  fs::File,
-  io::{Read, Seek, SeekFrom},
+  fs::{self, File, Metadata},
   ops::Deref,
+  path::{Component, Path, PathBuf},
+  str::FromStr,
   sync::OnceLock,
+  time::{SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
-pub const PARTIAL_CONTENT_SIZE: usize = 25 * 1024 * 1024;
-pub const ASSET_CHUNK_SIZE: usize = 512 * 1024;
+/// Most ranges served in a single `multipart/byteranges` response, requests for more
+/// get the full content
+pub const MAX_RANGES: usize = 16;
 
 #[derive(Debug, Error)]
 pub enum AssetError {
   #[error("Asset error [IO - {}]\n{0}", .0.kind())]
   IO(#[from] std::io::Error),
+  #[error("Asset error [Forbidden]\n{0:?} is outside the asset root")]
+  Forbidden(String),
+  #[error("Unknown symlink policy {0:?}")]
+  SymlinkPolicy(String),
 }
 
 pub type AssetResult<'a, T = Asset<'a>> = Result<T, AssetError>;
@@ -22,30 +29,23 @@ pub struct Asset<'a> {
   file: File,
   pub content_type: &'a str,
   pub size: usize,
+  pub identity: FileIdentity,
 }
 
 impl<'a> Asset<'a> {
   pub fn open(file_path: &str) -> AssetResult {
     let file = File::open(file_path)?;
+    let identity = file
+      .metadata()
+      .map(|m| FileIdentity::from(&m))
+      .unwrap_or_default();
     Ok(Asset {
-      size: file.metadata().map(|m| m.len() as usize).unwrap_or(0),
+      size: identity.size as usize,
+      identity,
       file,
       content_type: get_content_type(file_path),
     })
   }
-
-  pub fn read(&mut self, start: usize, end: usize) -> AssetResult<Vec<u8>> {
-    let mut buffer = vec![0u8; end - start];
-    self.file.seek(SeekFrom::Start(start as u64))?;
-    self.file.read_exact(&mut buffer)?;
-    Ok(buffer)
-  }
-
-  pub fn bytes(&mut self) -> AssetResult<Vec<u8>> {
-    let mut buffer = Vec::with_capacity(self.size);
-    self.file.read_to_end(&mut buffer)?;
-    Ok(buffer)
-  }
 }
 
 impl<'a> Deref for Asset<'a> {
@@ -55,6 +55,131 @@ impl<'a> Deref for Asset<'a> {
   }
 }
 
+/// Identity of a file on disk, changes whenever the file is replaced or modified
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileIdentity {
+  pub inode: u64,
+  pub modified: Option<SystemTime>,
+  pub size: u64,
+}
+
+impl FileIdentity {
+  pub fn from_path(path: &str) -> std::io::Result<Self> {
+    Ok(Self::from(&fs::metadata(path)?))
+  }
+
+  /// Strong entity tag of the file's content
+  pub fn etag(&self) -> String {
+    format!("\"{}\"", self.key())
+  }
+
+  /// Strong entity tag of a representation derived from the file, e.g. a thumbnail
+  /// rendered with the parameters described by `variant`
+  pub fn etag_with(&self, variant: &str) -> String {
+    format!("\"{}-{:x}\"", self.key(), fnv1a(variant.as_bytes()))
+  }
+
+  /// Stable key combining the identity fields
+  pub fn key(&self) -> String {
+    let modified = self
+      .modified
+      .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+      .map(|d| d.as_nanos())
+      .unwrap_or(0);
+    format!("{:x}-{modified:x}-{:x}", self.inode, self.size)
+  }
+}
+
+impl From<&Metadata> for FileIdentity {
+  fn from(metadata: &Metadata) -> Self {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+
+    Self {
+      inode,
+      modified: metadata.modified().ok(),
+      size: metadata.len(),
+    }
+  }
+}
+
+/// 64-bit FNV-1a, a hash that stays the same across builds unlike `DefaultHasher`
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// How symlinks found while resolving an asset path are treated
+#[derive(Debug, Default, Clone, Copy)]
+pub enum SymlinkPolicy {
+  /// Reject any path that goes through a symlink
+  Deny,
+  /// Follow symlinks as long as they resolve inside the root
+  #[default]
+  Contained,
+  /// Follow symlinks wherever they point
+  Follow,
+}
+
+impl FromStr for SymlinkPolicy {
+  type Err = AssetError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "deny" => Ok(Self::Deny),
+      "contained" => Ok(Self::Contained),
+      "follow" => Ok(Self::Follow),
+      _ => Err(AssetError::SymlinkPolicy(s.to_string())),
+    }
+  }
+}
+
+/// Joins the already decoded `relative` path onto `root`, failing with
+/// [`AssetError::Forbidden`] if the result escapes it
+pub fn resolve_path(
+  root: &str,
+  relative: &str,
+  policy: SymlinkPolicy,
+) -> Result<PathBuf, AssetError> {
+  let root = fs::canonicalize(root)?;
+  let mut path = root.clone();
+
+  for component in Path::new(relative).components() {
+    match component {
+      Component::Normal(segment) => {
+        path.push(segment);
+        if matches!(policy, SymlinkPolicy::Deny)
+          && fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink())
+        {
+          return Err(AssetError::Forbidden(relative.to_string()));
+        }
+      }
+      Component::ParentDir => {
+        if path == root {
+          return Err(AssetError::Forbidden(relative.to_string()));
+        }
+        path.pop();
+      }
+      // Leading slashes and drive prefixes are treated as relative to the root
+      Component::RootDir | Component::Prefix(..) | Component::CurDir => {}
+    }
+  }
+
+  if let SymlinkPolicy::Follow = policy {
+    fs::metadata(&path)?;
+    return Ok(path);
+  }
+
+  let resolved = fs::canonicalize(&path)?;
+  if !resolved.starts_with(&root) {
+    return Err(AssetError::Forbidden(relative.to_string()));
+  }
+
+  Ok(resolved)
+}
+
 static CONTENT_TYPES: OnceLock<HashMap<&str, &str>> = OnceLock::new();
 
 fn get_content_type<'a>(filepath: &str) -> &'a str {
@@ -131,3 +256,116 @@ fn get_content_type<'a>(filepath: &str) -> &'a str {
 
   "application/octet-stream"
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::http::decode_path;
+
+  /// Directory tree removed on drop, laid out as
+  /// `root/{file.txt, dir/nested.txt}` next to `outside/secret.txt`
+  struct TempTree(PathBuf);
+
+  impl TempTree {
+    fn new(name: &str) -> Self {
+      let base = std::env::temp_dir().join(format!("asset-{name}-{}", std::process::id()));
+      fs::create_dir_all(base.join("root/dir")).unwrap();
+      fs::create_dir_all(base.join("outside")).unwrap();
+      fs::write(base.join("root/file.txt"), "file").unwrap();
+      fs::write(base.join("root/dir/nested.txt"), "nested").unwrap();
+      fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+      Self(base)
+    }
+
+    fn root(&self) -> String {
+      self.0.join("root").to_string_lossy().to_string()
+    }
+
+    fn resolve(&self, raw_path: &str, policy: SymlinkPolicy) -> AssetResult<'_, PathBuf> {
+      resolve_path(&self.root(), &decode_path(raw_path).unwrap(), policy)
+    }
+  }
+
+  impl Drop for TempTree {
+    fn drop(&mut self) {
+      fs::remove_dir_all(&self.0).ok();
+    }
+  }
+
+  #[test]
+  fn resolves_inside_root() {
+    let tree = TempTree::new("inside");
+    let root = fs::canonicalize(tree.root()).unwrap();
+
+    let path = tree
+      .resolve("dir/nested.txt", SymlinkPolicy::Contained)
+      .unwrap();
+    assert_eq!(path, root.join("dir/nested.txt"));
+    let path = tree
+      .resolve("/dir/../file.txt", SymlinkPolicy::Contained)
+      .unwrap();
+    assert_eq!(path, root.join("file.txt"));
+    let path = tree
+      .resolve("dir%2fnested.txt", SymlinkPolicy::Contained)
+      .unwrap();
+    assert_eq!(path, root.join("dir/nested.txt"));
+  }
+
+  #[test]
+  fn rejects_parent_dir_escapes() {
+    let tree = TempTree::new("escape");
+
+    for raw_path in [
+      "../outside/secret.txt",
+      "dir/../../outside/secret.txt",
+      "%2e%2e/outside/secret.txt",
+      "%2E%2E%2Foutside%2Fsecret.txt",
+      "dir%2f..%2f..%2foutside%2fsecret.txt",
+    ] {
+      assert!(
+        matches!(
+          tree.resolve(raw_path, SymlinkPolicy::Follow),
+          Err(AssetError::Forbidden(_))
+        ),
+        "{raw_path} escaped the root"
+      );
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn applies_symlink_policy() {
+    use std::os::unix::fs::symlink;
+
+    let tree = TempTree::new("symlink");
+    symlink(tree.0.join("outside"), tree.0.join("root/escape")).unwrap();
+    symlink(tree.0.join("root/dir"), tree.0.join("root/alias")).unwrap();
+    let root = fs::canonicalize(tree.root()).unwrap();
+
+    let escaping = "escape/secret.txt";
+    let contained = "alias/nested.txt";
+
+    assert!(matches!(
+      tree.resolve(escaping, SymlinkPolicy::Deny),
+      Err(AssetError::Forbidden(_))
+    ));
+    assert!(matches!(
+      tree.resolve(contained, SymlinkPolicy::Deny),
+      Err(AssetError::Forbidden(_))
+    ));
+
+    assert!(matches!(
+      tree.resolve(escaping, SymlinkPolicy::Contained),
+      Err(AssetError::Forbidden(_))
+    ));
+    assert_eq!(
+      tree.resolve(contained, SymlinkPolicy::Contained).unwrap(),
+      root.join("dir/nested.txt")
+    );
+
+    let path = tree.resolve(escaping, SymlinkPolicy::Follow).unwrap();
+    assert_eq!(fs::read_to_string(path).unwrap(), "secret");
+    let path = tree.resolve(contained, SymlinkPolicy::Follow).unwrap();
+    assert_eq!(fs::read_to_string(path).unwrap(), "nested");
+  }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+/// Size of the buffer used to copy file ranges when `sendfile` is not available
+pub const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Content of an `HttpResponse`, only `Bytes` is held in memory
+pub enum HttpBody {
+  Bytes(Vec<u8>),
+  /// Parts of a file written straight from disk, interleaved with in-memory bytes
+  /// such as `multipart/byteranges` part headers
+  File(File, Vec<FilePart>),
+}
+
+#[derive(Debug)]
+pub enum FilePart {
+  Bytes(Vec<u8>),
+  /// `(offset, length)` of a range of the file
+  Range(u64, u64),
+}
+
+impl HttpBody {
+  /// Size in bytes
+  pub fn len(&self) -> u64 {
+    match self {
+      Self::Bytes(bytes) => bytes.len() as u64,
+      Self::File(_, parts) => parts
+        .iter()
+        .map(|part| match part {
+          FilePart::Bytes(bytes) => bytes.len() as u64,
+          FilePart::Range(_, length) => *length,
+        })
+        .sum(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn write_to(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+    match self {
+      Self::Bytes(bytes) => stream.write_all(bytes),
+      Self::File(file, parts) => {
+        for part in parts {
+          match part {
+            FilePart::Bytes(bytes) => stream.write_all(bytes)?,
+            FilePart::Range(offset, length) => copy_range(file, stream, *offset, *length)?,
+          }
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl Default for HttpBody {
+  fn default() -> Self {
+    Self::Bytes(Vec::new())
+  }
+}
+
+impl fmt::Debug for HttpBody {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+      Self::File(file, parts) => f.debug_tuple("File").field(file).field(parts).finish(),
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn copy_range(file: &mut File, stream: &mut TcpStream, offset: u64, length: u64) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  let mut offset = offset as libc::off_t;
+  let mut remaining = length;
+  while remaining > 0 {
+    let count = remaining.min(isize::MAX as u64) as usize;
+    let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+
+    match sent {
+      0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+      n if n > 0 => remaining -= n as u64,
+      _ => {
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+          Some(libc::EINTR) => continue,
+          // The file system does not support sendfile, copy whatever is left by hand
+          Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+            return copy_range_buffered(file, stream, offset as u64, remaining)
+          }
+          _ => return Err(e),
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_range(file: &mut File, stream: &mut TcpStream, offset: u64, length: u64) -> io::Result<()> {
+  copy_range_buffered(file, stream, offset, length)
+}
+
+fn copy_range_buffered(
+  file: &mut File,
+  stream: &mut TcpStream,
+  offset: u64,
+  length: u64,
+) -> io::Result<()> {
+  let mut buffer = vec![0u8; STREAM_BUFFER_SIZE];
+  let mut remaining = length;
+  file.seek(SeekFrom::Start(offset))?;
+
+  while remaining > 0 {
+    let size = remaining.min(STREAM_BUFFER_SIZE as u64) as usize;
+    file.read_exact(&mut buffer[..size])?;
+    stream.write_all(&buffer[..size])?;
+    remaining -= size as u64;
+  }
+  Ok(())
+}
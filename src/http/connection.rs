@@ -0,0 +1,239 @@
+use super::*;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+
+/// Largest request line plus headers accepted before answering with 431
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// Largest request body accepted before answering with 413
+pub const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+const READ_SIZE: usize = 8 * 1024;
+
+/// Buffered client connection that splits the incoming bytes into HTTP/1.1 requests,
+/// keeping any pipelined data for the next call to [`HttpConnection::read_request`]
+pub struct HttpConnection {
+  stream: TcpStream,
+  buffer: Vec<u8>,
+}
+
+impl HttpConnection {
+  pub fn new(stream: TcpStream) -> Self {
+    Self {
+      stream,
+      buffer: Vec::with_capacity(READ_SIZE),
+    }
+  }
+
+  /// Reads the head of the next request from the connection, returns `None` if the
+  /// client closed it between requests, its body is left for [`Self::read_body`]
+  ///
+  /// Framing errors are returned as an `HttpRequestError` so they can be answered,
+  /// IO errors mean the connection is no longer usable
+  pub fn read_request(&mut self) -> io::Result<Option<HttpRequestResult>> {
+    let Some(head_size) = self.read_head()? else {
+      return Ok(None);
+    };
+
+    if head_size > MAX_HEADER_SIZE {
+      return Ok(Some(Err(HttpRequestError::HeaderTooLarge(MAX_HEADER_SIZE))));
+    }
+
+    let head = self.buffer.drain(..head_size).collect::<Vec<_>>();
+    Ok(Some(HttpRequest::parse(&head)))
+  }
+
+  /// Reads the body of `request` into [`HttpRequest::body`], or only consumes it when
+  /// `keep` is `false`, so the next request starts in the right place either way
+  ///
+  /// Bodies over [`MAX_BODY_SIZE`] are answered with 413, whether kept or not
+  pub fn read_body(
+    &mut self,
+    request: &mut HttpRequest,
+    keep: bool,
+  ) -> io::Result<HttpRequestResult<()>> {
+    let mut body = Vec::new();
+    let mut sink = keep.then_some(&mut body);
+
+    let result = if request
+      .header("transfer-encoding")
+      .is_some_and(|te| te.to_lowercase().contains("chunked"))
+    {
+      self.read_chunked_body(sink)
+    } else if let Some(length) = request.header("content-length") {
+      match length.parse::<usize>() {
+        Ok(length) if length > MAX_BODY_SIZE => {
+          Err(HttpRequestError::BodyTooLarge(MAX_BODY_SIZE).into())
+        }
+        Ok(length) => self
+          .take(length, sink.as_deref_mut())
+          .map_err(BodyError::from),
+        Err(_) => Err(HttpRequestError::Body(format!("Invalid Content-Length {length:?}")).into()),
+      }
+    } else {
+      Ok(())
+    };
+
+    match result {
+      Ok(()) => {
+        request.body = body;
+        Ok(Ok(()))
+      }
+      Err(BodyError::Request(e)) => Ok(Err(e)),
+      Err(BodyError::IO(e)) => Err(e),
+    }
+  }
+
+  /// Fills the buffer until it holds a full request head and returns its size
+  /// including the blank line, or `None` if the connection was closed before any data came
+  fn read_head(&mut self) -> io::Result<Option<usize>> {
+    let mut searched: usize = 0;
+    loop {
+      // Ignore empty lines preceding the request line (RFC 7230 section 3.5)
+      let leading = self
+        .buffer
+        .iter()
+        .take_while(|b| **b == b'\r' || **b == b'\n')
+        .count();
+      self.buffer.drain(..leading);
+      searched = searched.saturating_sub(leading);
+
+      if let Some(i) = find(&self.buffer[searched..], b"\r\n\r\n") {
+        return Ok(Some(searched + i + 4));
+      }
+
+      if self.buffer.len() > MAX_HEADER_SIZE {
+        return Ok(Some(self.buffer.len()));
+      }
+
+      searched = self.buffer.len().saturating_sub(3);
+      if self.fill()? == 0 {
+        return if self.buffer.is_empty() {
+          Ok(None)
+        } else {
+          Err(io::ErrorKind::UnexpectedEof.into())
+        };
+      }
+    }
+  }
+
+  fn read_chunked_body(&mut self, mut body: Option<&mut Vec<u8>>) -> Result<(), BodyError> {
+    let mut body_size: usize = 0;
+    loop {
+      let line = self.take_line()?;
+      let line = String::from_utf8_lossy(&line);
+      let size = line.split(';').next().unwrap_or_default().trim();
+      let Ok(size) = usize::from_str_radix(size, 16) else {
+        return Err(HttpRequestError::Body(format!("Invalid chunk size {size:?}")).into());
+      };
+
+      if size == 0 {
+        break;
+      }
+
+      body_size = body_size.saturating_add(size);
+      if body_size > MAX_BODY_SIZE {
+        return Err(HttpRequestError::BodyTooLarge(MAX_BODY_SIZE).into());
+      }
+
+      self.take(size, body.as_deref_mut())?;
+      if !self.take_line()?.is_empty() {
+        return Err(HttpRequestError::Body("Chunk is longer than its size".into()).into());
+      }
+    }
+
+    // Trailers are not used, skip them until the terminating empty line
+    while !self.take_line()?.is_empty() {}
+
+    Ok(())
+  }
+
+  /// Removes a `\r\n` terminated line from the buffer, without the terminator
+  fn take_line(&mut self) -> Result<Vec<u8>, BodyError> {
+    let mut searched = 0;
+    loop {
+      if let Some(i) = find(&self.buffer[searched..], b"\r\n") {
+        let mut line = self.buffer.drain(..searched + i + 2).collect::<Vec<_>>();
+        line.truncate(line.len() - 2);
+        return Ok(line);
+      }
+
+      if self.buffer.len() > MAX_HEADER_SIZE {
+        return Err(
+          HttpRequestError::Body(format!("Chunk line exceeds {MAX_HEADER_SIZE} bytes")).into(),
+        );
+      }
+
+      searched = self.buffer.len().saturating_sub(1);
+      if self.fill()? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+      }
+    }
+  }
+
+  /// Removes exactly `size` bytes from the buffer, reading more if needed, and appends
+  /// them to `body` unless it's `None`
+  fn take(&mut self, mut size: usize, mut body: Option<&mut Vec<u8>>) -> io::Result<()> {
+    loop {
+      let available = self.buffer.len().min(size);
+      let bytes = self.buffer.drain(..available);
+      match body.as_deref_mut() {
+        Some(body) => body.extend(bytes),
+        None => drop(bytes),
+      }
+      size -= available;
+      if size == 0 {
+        return Ok(());
+      }
+      if self.fill()? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+      }
+    }
+  }
+
+  fn fill(&mut self) -> io::Result<usize> {
+    let start = self.buffer.len();
+    self.buffer.resize(start + READ_SIZE, 0);
+    let read = self.stream.read(&mut self.buffer[start..]);
+    self.buffer.truncate(start + *read.as_ref().unwrap_or(&0));
+    read
+  }
+}
+
+impl Deref for HttpConnection {
+  type Target = TcpStream;
+
+  fn deref(&self) -> &Self::Target {
+    &self.stream
+  }
+}
+
+impl DerefMut for HttpConnection {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.stream
+  }
+}
+
+/// Why a body could not be read, invalid requests can still be answered while
+/// IO errors leave the connection unusable
+enum BodyError {
+  Request(HttpRequestError),
+  IO(io::Error),
+}
+
+impl From<HttpRequestError> for BodyError {
+  fn from(e: HttpRequestError) -> Self {
+    Self::Request(e)
+  }
+}
+
+impl From<io::Error> for BodyError {
+  fn from(e: io::Error) -> Self {
+    Self::IO(e)
+  }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+  haystack
+    .windows(needle.len())
+    .position(|window| window == needle)
+}
@@ -0,0 +1,79 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+  "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+  let secs = time
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+  let days = (secs / 86400) as i64;
+  let (year, month, day) = civil_from_days(days);
+  let secs = secs % 86400;
+
+  format!(
+    "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+    DAYS[(days % 7) as usize],
+    MONTHS[month as usize - 1],
+    secs / 3600,
+    secs / 60 % 60,
+    secs % 60,
+  )
+}
+
+/// Parses an IMF-fixdate, the only format servers are required to send
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+  let mut parts = date.trim().split_whitespace().skip(1);
+  let day = parts.next()?.parse::<u32>().ok()?;
+  let month = parts.next()?;
+  let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+  let year = parts.next()?.parse::<i64>().ok()?;
+  let mut time = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+  let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+  if parts.next() != Some("GMT") || hours > 23 || minutes > 59 || seconds > 60 {
+    return None;
+  }
+
+  let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+  Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hours * 3600 + minutes * 60 + seconds))
+}
+
+/// Truncates `time` to whole seconds, the precision of HTTP dates
+pub fn http_date_secs(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// *Based on Howard Hinnant's
+/// [`civil_from_days`](http://howardhinnant.github.io/date_algorithms.html#civil_from_days)*
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (year, month, day)
+}
+
+/// *Based on Howard Hinnant's
+/// [`days_from_civil`](http://howardhinnant.github.io/date_algorithms.html#days_from_civil)*
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = year - i64::from(month <= 2);
+  let era = year.div_euclid(400);
+  let yoe = year.rem_euclid(400);
+  let mp = (month as i64 + 9) % 12;
+  let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146097 + doe - 719468
+}
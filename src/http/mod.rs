@@ -1,18 +1,27 @@
 mod asset;
+mod body;
+mod connection;
+mod date;
 mod parse;
+mod pool;
 mod request;
 mod response;
 mod server;
 
 pub use asset::*;
+pub use body::*;
+pub use connection::*;
+pub use date::*;
 pub use parse::*;
+pub use pool::*;
 pub use request::*;
 pub use response::*;
 pub use server::*;
 
+use crate::audio::AudioError;
 use crate::rumpeg::RumpegError;
 use crate::video::VideoError;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,9 +30,11 @@ pub enum ServerError {
   IO(#[from] std::io::Error),
   #[error("Server Error [Video]\n{0}")]
   Video(#[from] VideoError),
+  #[error("Server Error [Audio]\n{0}")]
+  Audio(#[from] AudioError),
   #[error("Server Error [WebP]\n{0}")]
   WebP(#[from] RumpegError),
-  #[error("Failed to set Ctrl+C handler")]
+  #[error("Failed to set exit signal handler")]
   ExitHandler,
   #[error("Server Error [WebP]\n{0}")]
   BadRequest(#[from] HttpRequestError),
@@ -33,11 +44,27 @@ pub enum ServerError {
 
 pub type ServerResult<T = ()> = Result<T, ServerError>;
 
+static CTRL_C_PRESSED: AtomicBool = AtomicBool::new(false);
+static EXIT_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Status code the process should exit with once the server stops listening,
+/// following the shell convention of `128 + signal number`
+pub fn exit_code() -> i32 {
+  match EXIT_SIGNAL.load(Ordering::SeqCst) {
+    0 => 0,
+    signal => 128 + signal,
+  }
+}
+
+#[cfg(windows)]
 const TRUE: i32 = 1;
+#[cfg(windows)]
 const FALSE: i32 = 0;
 
+#[cfg(windows)]
 const CTRL_C_EVENT: u32 = 0;
 
+#[cfg(windows)]
 extern "system" {
   fn SetConsoleCtrlHandler(
     handlerRoutine: Option<unsafe extern "system" fn(dwCtrlType: u32) -> i32>,
@@ -45,14 +72,79 @@ extern "system" {
   ) -> i32;
 }
 
-static CTRL_C_PRESSED: AtomicBool = AtomicBool::new(false);
-
+#[cfg(windows)]
 unsafe extern "system" fn ctrl_handler(ctrl_type: u32) -> i32 {
   match ctrl_type {
     CTRL_C_EVENT => {
+      // SIGINT
+      EXIT_SIGNAL.store(2, Ordering::SeqCst);
       CTRL_C_PRESSED.store(true, Ordering::SeqCst);
       TRUE
     }
     _ => FALSE,
   }
 }
+
+#[cfg(windows)]
+fn set_exit_handler() -> ServerResult {
+  if unsafe { SetConsoleCtrlHandler(Some(ctrl_handler), TRUE) } == FALSE {
+    return Err(ServerError::ExitHandler);
+  }
+  Ok(())
+}
+
+/// Self-pipe written to by the signal handler so the accept loop can block on `poll`
+/// and still wake up on exit, `[read, write]`
+#[cfg(unix)]
+static EXIT_PIPE: [AtomicI32; 2] = [AtomicI32::new(-1), AtomicI32::new(-1)];
+
+#[cfg(unix)]
+pub(super) fn exit_pipe_fd() -> libc::c_int {
+  EXIT_PIPE[0].load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+extern "C" fn signal_handler(signal: libc::c_int) {
+  EXIT_SIGNAL.store(signal, Ordering::SeqCst);
+  CTRL_C_PRESSED.store(true, Ordering::SeqCst);
+
+  let fd = EXIT_PIPE[1].load(Ordering::SeqCst);
+  if fd >= 0 {
+    unsafe {
+      libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+    }
+  }
+}
+
+#[cfg(unix)]
+fn set_exit_handler() -> ServerResult {
+  unsafe {
+    if EXIT_PIPE[0].load(Ordering::SeqCst) < 0 {
+      let mut fds = [-1; 2];
+      if libc::pipe(fds.as_mut_ptr()) != 0 {
+        return Err(ServerError::ExitHandler);
+      }
+      for fd in fds {
+        libc::fcntl(
+          fd,
+          libc::F_SETFL,
+          libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK,
+        );
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+      }
+      EXIT_PIPE[0].store(fds[0], Ordering::SeqCst);
+      EXIT_PIPE[1].store(fds[1], Ordering::SeqCst);
+    }
+
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = signal_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+      if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+        return Err(ServerError::ExitHandler);
+      }
+    }
+  }
+  Ok(())
+}
@@ -1,5 +1,12 @@
-use super::HttpRequestResult;
-use std::str::FromStr;
+use super::{HttpRequestError, HttpRequestResult};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::{from_utf8, FromStr};
+
+/// Named captures of a matched route pattern
+pub type PathParams = HashMap<String, String>;
+/// Decoded `key=value` pairs of a query string, in order
+pub type QueryParams = Vec<(String, String)>;
 
 pub trait FromQueryString {
   fn from_query_string(query_string: &str) -> HttpRequestResult<Self>
@@ -8,43 +15,183 @@ pub trait FromQueryString {
 }
 
 pub trait FromPath {
-  fn from_path(path: &str) -> HttpRequestResult<Self>
+  fn from_path(params: &PathParams) -> HttpRequestResult<Self>
   where
     Self: std::marker::Sized;
 }
 
-pub fn find_query_flag(query: &[&str], key_name: &str) -> bool {
-  query.iter().any(|key| *key == key_name)
+/// Implements [`FromPath`] for a struct, parsing every listed field from the
+/// path capture with the same name
+/// ```ignore
+/// struct FrameParams {
+///   stream: i32,
+///   path: String,
+/// }
+///
+/// from_path!(FrameParams { stream, path });
+/// ```
+#[macro_export]
+macro_rules! from_path {
+  ( $name: ident { $( $field: ident ),* $(,)? } ) => {
+    impl $crate::http::FromPath for $name {
+      fn from_path(params: &$crate::http::PathParams) -> $crate::http::HttpRequestResult<Self> {
+        Ok(Self {
+          $( $field: $crate::http::find_path_param(params, stringify!($field))?, )*
+        })
+      }
+    }
+  };
+}
+
+pub fn find_path_param<F: FromStr>(params: &PathParams, name: &str) -> HttpRequestResult<F> {
+  let param = params
+    .get(name)
+    .ok_or_else(|| HttpRequestError::Parse(format!("Missing path parameter {name:?}")))?;
+  param
+    .parse::<F>()
+    .map_err(|_| HttpRequestError::Parse(format!("Invalid path parameter {name}={param:?}")))
 }
 
-pub fn find_query_arg<F: FromStr + Default>(query: &[&str], key_name: &str) -> F {
+/// Matches the still encoded `path` against a route `pattern` and returns its decoded captures,
+/// so escaped slashes never split a segment
+///
+/// - `:name` captures a single non-empty segment
+/// - `*name` captures the rest of the path, `*` alone matches it without capturing
+///
+/// A segment with invalid percent-encoding is an error rather than a mismatch
+pub fn match_route(pattern: &str, path: &str) -> HttpRequestResult<Option<PathParams>> {
+  let mut params = PathParams::new();
+  let mut path_segments = path.trim_start_matches('/').split('/');
+
+  for segment in pattern.trim_start_matches('/').split('/') {
+    if let Some(name) = segment.strip_prefix('*') {
+      let rest = path_segments.collect::<Vec<_>>().join("/");
+      if !name.is_empty() {
+        params.insert(name.to_string(), decode_path(&rest)?);
+      }
+      return Ok(Some(params));
+    }
+
+    let Some(path_segment) = path_segments.next() else {
+      return Ok(None);
+    };
+    let path_segment = decode_path(path_segment)?;
+    if let Some(name) = segment.strip_prefix(':') {
+      if path_segment.is_empty() {
+        return Ok(None);
+      }
+      params.insert(name.to_string(), path_segment);
+    } else if segment != path_segment {
+      return Ok(None);
+    }
+  }
+
+  Ok(path_segments.next().is_none().then_some(params))
+}
+
+/// Splits a raw query string into its decoded key value pairs
+pub fn parse_query(query_string: &str) -> HttpRequestResult<QueryParams> {
+  query_string
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| {
+      let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+      Ok((decode_query_key(key)?, decode_query_value(value)?))
+    })
+    .collect()
+}
+
+/// Whether `key_name` is present in the query, unless its value is `false` or `0`
+pub fn find_query_flag(query: &QueryParams, key_name: &str) -> bool {
   query
     .iter()
-    .find(|q| q.starts_with(&format!("{key_name}=")))
-    .and_then(|q| q.split_once('='))
-    .map(|q| q.1)
-    .and_then(|n| n.parse::<F>().ok())
-    .unwrap_or_default()
-}
-
-pub(super) fn decode_uri(uri: &str) -> String {
-  let mut decoded_uri = String::with_capacity(uri.len());
-  let mut chars = uri.chars();
-
-  while let Some(c) = chars.next() {
-    match c {
-      '%' => {
-        let encoded_byte = chars.by_ref().take(2).collect::<String>();
-        if let Ok(byte) = u8::from_str_radix(&encoded_byte, 16) {
-          decoded_uri.push(char::from(byte));
-        } else {
-          decoded_uri.push('%');
-          decoded_uri.push_str(&encoded_byte);
-        }
+    .any(|(key, value)| key == key_name && value != "false" && value != "0")
+}
+
+/// Like [`find_query_flag`], but `default` when the key is absent
+pub fn find_query_flag_or(query: &QueryParams, key_name: &str, default: bool) -> bool {
+  match query.iter().any(|(key, _)| key == key_name) {
+    true => find_query_flag(query, key_name),
+    false => default,
+  }
+}
+
+pub fn find_query_arg<F: FromStr + Default>(query: &QueryParams, key_name: &str) -> F {
+  find_query_arg_or(query, key_name, F::default())
+}
+
+/// Like [`find_query_arg`], but `default` when the key is absent or does not parse
+pub fn find_query_arg_or<F: FromStr>(query: &QueryParams, key_name: &str, default: F) -> F {
+  query
+    .iter()
+    .find(|(key, _)| key == key_name)
+    .and_then(|(_, value)| value.parse::<F>().ok())
+    .unwrap_or(default)
+}
+
+/// Like [`find_query_arg`], but `None` when the key is absent and an error when its
+/// value does not parse
+pub fn try_find_query_arg<F: FromStr>(
+  query: &QueryParams,
+  key_name: &str,
+) -> HttpRequestResult<Option<F>>
+where
+  F::Err: Display,
+{
+  query
+    .iter()
+    .find(|(key, _)| key == key_name)
+    .map(|(_, value)| {
+      value
+        .parse()
+        .map_err(|e| HttpRequestError::Parse(format!("{key_name}: {e}")))
+    })
+    .transpose()
+}
+
+/// Decodes a URL path, `+` is kept as is
+pub fn decode_path(path: &str) -> HttpRequestResult<String> {
+  percent_decode(path, false)
+}
+
+/// Decodes a form encoded query string key, `+` is decoded as a space
+pub fn decode_query_key(key: &str) -> HttpRequestResult<String> {
+  percent_decode(key, true)
+}
+
+/// Decodes a form encoded query string value, `+` is decoded as a space
+pub fn decode_query_value(value: &str) -> HttpRequestResult<String> {
+  percent_decode(value, true)
+}
+
+/// Decodes every `%XX` escape into its raw byte and validates the result as UTF-8,
+/// so multi-byte characters encoded one byte at a time come out whole
+fn percent_decode(input: &str, plus_as_space: bool) -> HttpRequestResult<String> {
+  let bytes = input.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+
+  while i < bytes.len() {
+    match bytes[i] {
+      b'%' => {
+        let byte = bytes
+          .get(i + 1..i + 3)
+          .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+          .and_then(|hex| u8::from_str_radix(from_utf8(hex).ok()?, 16).ok())
+          .ok_or_else(|| HttpRequestError::PercentEncoding(input.to_string()))?;
+        decoded.push(byte);
+        i += 3;
+      }
+      b'+' if plus_as_space => {
+        decoded.push(b' ');
+        i += 1;
+      }
+      byte => {
+        decoded.push(byte);
+        i += 1;
       }
-      _ => decoded_uri.push(c),
     }
   }
 
-  decoded_uri
+  String::from_utf8(decoded).map_err(|e| e.utf8_error().into())
 }
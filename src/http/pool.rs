@@ -0,0 +1,104 @@
+use super::*;
+use crate::ascii::LogDisplay;
+use crate::log;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub type Connection = (TcpStream, SocketAddr);
+
+/// Fixed set of worker threads serving connections from a bounded queue
+pub struct ThreadPool {
+  sender: Option<SyncSender<Connection>>,
+  workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+  pub fn new(
+    size: usize,
+    queue_size: usize,
+    router: Arc<Router>,
+    keep_alive_slots: Arc<KeepAliveSlots>,
+  ) -> ServerResult<Self> {
+    let (sender, receiver) = sync_channel(queue_size);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let mut workers = Vec::with_capacity(size);
+
+    for id in 0..size {
+      let receiver = receiver.clone();
+      let router = router.clone();
+      let keep_alive_slots = keep_alive_slots.clone();
+      workers.push(
+        thread::Builder::new()
+          .name(format!("Worker {id}"))
+          .spawn(move || work(receiver, router, keep_alive_slots))?,
+      );
+    }
+
+    Ok(Self {
+      sender: Some(sender),
+      workers,
+    })
+  }
+
+  pub fn size(&self) -> usize {
+    self.workers.len()
+  }
+
+  /// Queues a connection to be served by the next idle worker, giving it back
+  /// if the queue is full
+  pub fn execute(&self, connection: Connection) -> Result<(), Connection> {
+    let Some(ref sender) = self.sender else {
+      return Err(connection);
+    };
+
+    match sender.try_send(connection) {
+      Ok(()) => Ok(()),
+      Err(TrySendError::Full(connection)) | Err(TrySendError::Disconnected(connection)) => {
+        Err(connection)
+      }
+    }
+  }
+
+  /// Stops accepting connections and waits for the workers to finish the queued ones
+  pub fn join(&mut self) {
+    drop(self.sender.take());
+    for worker in self.workers.drain(..) {
+      let name = worker
+        .thread()
+        .name()
+        .unwrap_or("Unnamed Worker")
+        .to_string();
+      if worker.join().is_err() {
+        log!(err@"[{name}] Worker thread panicked");
+      }
+    }
+  }
+}
+
+impl Drop for ThreadPool {
+  fn drop(&mut self) {
+    self.join();
+  }
+}
+
+fn work(
+  receiver: Arc<Mutex<Receiver<Connection>>>,
+  router: Arc<Router>,
+  keep_alive_slots: Arc<KeepAliveSlots>,
+) {
+  loop {
+    let connection = match receiver.lock() {
+      Ok(receiver) => receiver.recv(),
+      Err(_) => break,
+    };
+
+    let Ok((stream, addr)) = connection else {
+      break;
+    };
+    if let Err(e) = serve_client(stream, addr, router.clone(), &keep_alive_slots) {
+      log!(err@"[{addr}] Connection error\n{e}");
+    }
+  }
+}
@@ -1,15 +1,19 @@
 use super::*;
 use std::collections::HashMap;
-use std::str::{from_utf8, Utf8Error};
+use std::str::{from_utf8, FromStr, Utf8Error};
 use thiserror::Error;
 
 #[derive(Debug)]
 pub struct HttpRequest {
   pub method: HttpMethod,
-  pub path: String,
+  /// Still percent-encoded, routes decode the captures they match
+  pub raw_path: String,
   pub query_string: String,
+  pub params: PathParams,
   pub headers: HashMap<String, String>,
   pub http_version: String,
+  /// Filled in by [`HttpConnection::read_body`] for requests a route takes
+  pub body: Vec<u8>,
 }
 
 impl HttpRequest {
@@ -17,15 +21,21 @@ impl HttpRequest {
     let request = from_utf8(raw_data)?;
 
     let lines: Vec<&str> = request.lines().collect();
+    let Some(request_line) = lines.first() else {
+      return Err(HttpRequestError::Header(String::new()));
+    };
 
-    let request_line_parts: Vec<&str> = lines[0].split_whitespace().collect();
+    let request_line_parts: Vec<&str> = request_line.split_whitespace().collect();
     if request_line_parts.len() != 3 {
-      return Err(HttpRequestError::Header(lines[0].to_string()));
+      return Err(HttpRequestError::Header(request_line.to_string()));
     }
 
     let method = request_line_parts[0].try_into()?;
-    let path = decode_uri(request_line_parts[1]);
-    let (path, query_string) = path.split_once('?').unwrap_or((&path, ""));
+    let (raw_path, query_string) = request_line_parts[1]
+      .split_once('?')
+      .unwrap_or((request_line_parts[1], ""));
+    // Malformed escapes are rejected up front instead of failing to match any route
+    decode_path(raw_path)?;
     let http_version = request_line_parts[2].to_string();
 
     let mut headers = HashMap::new();
@@ -37,35 +47,43 @@ impl HttpRequest {
 
     Ok(Self {
       method,
-      path: path.to_string(),
+      raw_path: raw_path.to_string(),
       http_version,
       headers,
       query_string: query_string.to_string(),
+      params: PathParams::new(),
+      body: Vec::new(),
     })
   }
 
-  pub fn range(&self) -> Option<(usize, usize)> {
-    self.headers.get("range").and_then(|r| {
-      r.split_once('=').and_then(|r| {
-        r.1.find('-').map(|i| {
-          let start = r
-            .1
-            .get(..i)
-            .map(|s| s.parse::<usize>().unwrap_or(0))
-            .unwrap_or(0);
-          let end = r
-            .1
-            .get(i + 1..r.1.len())
-            .map(|s| s.parse::<usize>().unwrap_or(start + ASSET_CHUNK_SIZE))
-            .unwrap_or(start + ASSET_CHUNK_SIZE);
-          (start, end)
-        })
-      })
-    })
+  /// Looks up a header by its case-insensitive name
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.get(&name.to_lowercase()).map(|h| h.as_str())
+  }
+
+  /// Whether the connection should stay open after responding to this request
+  pub fn keep_alive(&self) -> bool {
+    match self.header("connection").map(|c| c.to_lowercase()) {
+      Some(c) if c.contains("close") => false,
+      Some(c) if c.contains("keep-alive") => true,
+      _ => self.http_version == "HTTP/1.1",
+    }
+  }
+
+  /// Byte ranges requested by the `Range` header, `None` if it's missing or
+  /// malformed, in which case it must be ignored (RFC 7233 section 3.1)
+  pub fn range(&self) -> Option<Vec<ByteRange>> {
+    self
+      .header("range")?
+      .trim()
+      .strip_prefix("bytes=")?
+      .split(',')
+      .map(|range| range.trim().parse().ok())
+      .collect()
   }
 
   pub fn path<Q: FromPath>(&self) -> HttpRequestResult<Q> {
-    Q::from_path(&self.path)
+    Q::from_path(&self.params)
   }
 
   pub fn query<Q: FromQueryString>(&self) -> HttpRequestResult<Q> {
@@ -73,32 +91,129 @@ impl HttpRequest {
   }
 }
 
+/// A single range of a `Range: bytes=` header, positions are inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+  /// `start-end`
+  Bounded(usize, usize),
+  /// `start-`, until the end of the representation
+  From(usize),
+  /// `-length`, the last `length` bytes of the representation
+  Suffix(usize),
+}
+
+impl ByteRange {
+  /// Resolves the range against a representation of `size` bytes into inclusive
+  /// `(start, end)` positions, `None` if it's not satisfiable
+  pub fn resolve(&self, size: usize) -> Option<(usize, usize)> {
+    let last = size.checked_sub(1)?;
+    let (start, end) = match *self {
+      Self::Bounded(start, end) => (start, end.min(last)),
+      Self::From(start) => (start, last),
+      Self::Suffix(0) => return None,
+      Self::Suffix(length) => (size.saturating_sub(length), last),
+    };
+    (start <= end).then_some((start, end))
+  }
+}
+
+impl FromStr for ByteRange {
+  type Err = HttpRequestError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || HttpRequestError::Range(s.to_string());
+    let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+    let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| invalid());
+
+    match (start.trim().is_empty(), end.trim().is_empty()) {
+      (true, false) => Ok(Self::Suffix(parse(end)?)),
+      (false, true) => Ok(Self::From(parse(start)?)),
+      (false, false) => {
+        let (start, end) = (parse(start)?, parse(end)?);
+        if end < start {
+          return Err(invalid());
+        }
+        Ok(Self::Bounded(start, end))
+      }
+      (true, true) => Err(invalid()),
+    }
+  }
+}
+
 #[derive(Debug, Error)]
 pub enum HttpRequestError {
   #[error("Request is not valid utf8\n{0}")]
   Data(#[from] Utf8Error),
   #[error("Invalid request header {0:?}")]
   Header(String),
+  #[error("Invalid byte range {0:?}")]
+  Range(String),
+  #[error("Invalid percent-encoding in {0:?}")]
+  PercentEncoding(String),
   #[error("Invalid request method {0:?}")]
   Method(String),
   #[error("Could not parse request {0}")]
   Parse(String),
+  #[error("Access to {0:?} is forbidden")]
+  Forbidden(String),
+  #[error("{0:?} was not found")]
+  NotFound(String),
+  #[error("{0}")]
+  UnsupportedMedia(String),
+  #[error("Request header exceeds {0} bytes")]
+  HeaderTooLarge(usize),
+  #[error("Request body exceeds {0} bytes")]
+  BodyTooLarge(usize),
+  #[error("Invalid request body: {0}")]
+  Body(String),
 }
 
 pub type HttpRequestResult<T = HttpRequest> = Result<T, HttpRequestError>;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpMethod {
   Get,
+  Head,
+  Post,
+  Put,
+  Delete,
+  Options,
+}
+
+impl HttpMethod {
+  pub const ALL: [Self; 6] = [
+    Self::Get,
+    Self::Head,
+    Self::Post,
+    Self::Put,
+    Self::Delete,
+    Self::Options,
+  ];
+
+  pub fn as_str<'a>(&self) -> &'a str {
+    match self {
+      Self::Get => "GET",
+      Self::Head => "HEAD",
+      Self::Post => "POST",
+      Self::Put => "PUT",
+      Self::Delete => "DELETE",
+      Self::Options => "OPTIONS",
+    }
+  }
 }
 
 impl TryFrom<&str> for HttpMethod {
   type Error = HttpRequestError;
   fn try_from(value: &str) -> Result<Self, Self::Error> {
-    match value {
-      "GET" => Ok(Self::Get),
-      method => Err(HttpRequestError::Method(method.to_string())),
-    }
+    Self::ALL
+      .into_iter()
+      .find(|method| method.as_str() == value)
+      .ok_or_else(|| HttpRequestError::Method(value.to_string()))
+  }
+}
+
+impl std::fmt::Display for HttpMethod {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.as_str())
   }
 }
 
@@ -1,24 +1,44 @@
 use super::*;
-use std::{collections::HashMap, io::Write, net::TcpStream};
+use std::{collections::HashMap, io::Write, net::TcpStream, time::SystemTime};
 
 #[derive(Debug, Default)]
 pub enum HttpStatus {
   #[default]
   OK,
+  NoContent,
   PartialContent,
+  NotModified,
   BadRequest(HttpRequestError),
+  Forbidden,
   NotFound,
+  MethodNotAllowed,
+  PayloadTooLarge(HttpRequestError),
+  UnsupportedMediaType(HttpRequestError),
+  RangeNotSatisfiable,
+  RequestHeaderFieldsTooLarge(HttpRequestError),
   InternalServerError(ServerError),
+  NotImplemented(HttpRequestError),
+  ServiceUnavailable,
 }
 
 impl HttpStatus {
   fn as_tuple<'a>(&self) -> (u16, &'a str) {
     match *self {
       HttpStatus::OK => (200, "OK"),
+      HttpStatus::NoContent => (204, "No Content"),
       HttpStatus::PartialContent => (206, "Partial Content"),
+      HttpStatus::NotModified => (304, "Not Modified"),
       HttpStatus::BadRequest(..) => (400, "Bad Request"),
+      HttpStatus::Forbidden => (403, "Forbidden"),
       HttpStatus::NotFound => (404, "Not Found"),
+      HttpStatus::MethodNotAllowed => (405, "Method Not Allowed"),
+      HttpStatus::PayloadTooLarge(..) => (413, "Payload Too Large"),
+      HttpStatus::UnsupportedMediaType(..) => (415, "Unsupported Media Type"),
+      HttpStatus::RangeNotSatisfiable => (416, "Range Not Satisfiable"),
+      HttpStatus::RequestHeaderFieldsTooLarge(..) => (431, "Request Header Fields Too Large"),
       HttpStatus::InternalServerError(..) => (500, "Internal Server Error"),
+      HttpStatus::NotImplemented(..) => (501, "Not Implemented"),
+      HttpStatus::ServiceUnavailable => (503, "Service Unavailable"),
     }
   }
 }
@@ -27,7 +47,8 @@ impl HttpStatus {
 pub struct HttpResponse {
   status_code: HttpStatus,
   headers: HashMap<String, String>,
-  content: Vec<u8>,
+  body: HttpBody,
+  omit_content: bool,
 }
 
 impl Default for HttpResponse {
@@ -39,7 +60,8 @@ impl Default for HttpResponse {
         ("Cache-Control".to_string(), "no-cache".to_string()),
         ("Content-Length".to_string(), 0.to_string()),
       ]),
-      content: Vec::new(),
+      body: HttpBody::default(),
+      omit_content: false,
     }
   }
 }
@@ -47,49 +69,161 @@ impl Default for HttpResponse {
 impl HttpResponse {
   pub fn from_asset(asset_path: &str, request: &HttpRequest) -> ServerResult<Self> {
     let mut response = Self::default();
-    let mut asset = Asset::open(asset_path)?;
-    response.add_asset(&mut asset, request.range())?;
+    let asset = Asset::open(asset_path)?;
+    response.add_asset(&asset, request)?;
 
     Ok(response)
   }
 
+  pub fn status(&self) -> &HttpStatus {
+    &self.status_code
+  }
+
   pub fn add_header(&mut self, key: &str, value: &str) {
     self.headers.insert(key.to_string(), value.to_string());
   }
 
+  pub fn remove_header(&mut self, key: &str) {
+    self.headers.remove(key);
+  }
+
   pub fn add_content(&mut self, content: &[u8]) {
-    self.add_header("Content-Length", &content.len().to_string());
-    self.content.extend_from_slice(content);
+    let mut bytes = match std::mem::take(&mut self.body) {
+      HttpBody::Bytes(bytes) => bytes,
+      _ => Vec::new(),
+    };
+    bytes.extend_from_slice(content);
+    self.set_body(HttpBody::Bytes(bytes));
   }
 
-  pub fn add_asset(&mut self, asset: &mut Asset, range: Option<(usize, usize)>) -> ServerResult {
-    self.add_header("Content-Type", asset.content_type);
+  /// Replaces the content, setting its `Content-Length`
+  pub fn set_body(&mut self, body: HttpBody) {
+    self.add_header("Content-Length", &body.len().to_string());
+    self.body = body;
+  }
+
+  /// Adds the `ETag` and `Last-Modified` validators, and turns the response into
+  /// a 304 if the request's conditional headers show the client already has this
+  /// representation (RFC 7232 section 6), returns whether it did
+  pub fn check_validators(
+    &mut self,
+    request: &HttpRequest,
+    etag: &str,
+    last_modified: Option<SystemTime>,
+  ) -> bool {
+    self.add_header("ETag", etag);
+    if let Some(modified) = last_modified {
+      self.add_header("Last-Modified", &http_date(modified));
+    }
 
-    let content = if range.is_some() || asset.size > PARTIAL_CONTENT_SIZE {
-      let length = asset.size;
-      let (start, end) = range.unwrap_or((0, ASSET_CHUNK_SIZE));
-      let end = std::cmp::min(
-        match end - start {
-          s if s > ASSET_CHUNK_SIZE || s == 0 => start + ASSET_CHUNK_SIZE,
-          _ => end,
-        },
-        length,
-      );
-
-      self.set_status(HttpStatus::PartialContent);
-      self.add_header("Connection", "keep-alive");
-      self.add_header("Keep-Alive", "timeout=5");
-      self.add_header("Accept-Ranges", "bytes");
-      self.add_header("Content-Range", &format!("bytes {start}-{end}/{length}"));
-
-      asset.read(start, end)?
+    let not_modified = if let Some(tags) = request.header("if-none-match") {
+      tags.trim() == "*"
+        || tags
+          .split(',')
+          .any(|tag| weak_tag(tag.trim()) == weak_tag(etag))
+    } else if let (Some(since), Some(modified)) = (
+      request
+        .header("if-modified-since")
+        .and_then(parse_http_date),
+      last_modified,
+    ) {
+      matches!(request.method, HttpMethod::Get | HttpMethod::Head)
+        && http_date_secs(modified) <= http_date_secs(since)
     } else {
-      asset.bytes()?
+      false
     };
-    self.add_content(&content);
+
+    if not_modified {
+      self.set_status(HttpStatus::NotModified);
+      self.body = HttpBody::default();
+      self.remove_header("Content-Length");
+      self.remove_header("Content-Type");
+    }
+
+    not_modified
+  }
+
+  /// Adds `asset` as the content, honoring the request's conditional, `Range`
+  /// and `If-Range` headers
+  pub fn add_asset(&mut self, asset: &Asset, request: &HttpRequest) -> ServerResult {
+    let etag = asset.identity.etag();
+    if self.check_validators(request, &etag, asset.identity.modified) {
+      return Ok(());
+    }
+
+    self.add_header("Content-Type", asset.content_type);
+    self.add_header("Accept-Ranges", "bytes");
+
+    let size = asset.size;
+    let file = asset.try_clone()?;
+    // Too many ranges are ignored like any other unusable `Range` header, the full
+    // content is cheaper to send than a huge multipart body
+    let ranges = request
+      .range()
+      .filter(|ranges| ranges.len() <= MAX_RANGES)
+      .filter(|_| if_range_matches(request, asset, &etag));
+
+    let Some(ranges) = ranges else {
+      self.set_body(HttpBody::File(file, vec![FilePart::Range(0, size as u64)]));
+      return Ok(());
+    };
+
+    let ranges = ranges
+      .iter()
+      .filter_map(|range| range.resolve(size))
+      .map(|(start, end)| (start as u64, end as u64))
+      .collect::<Vec<_>>();
+
+    match ranges[..] {
+      [] => {
+        self.set_status(HttpStatus::RangeNotSatisfiable);
+        self.add_header("Content-Range", &format!("bytes */{size}"));
+      }
+      [(start, end)] => {
+        self.set_status(HttpStatus::PartialContent);
+        self.add_header("Content-Range", &format!("bytes {start}-{end}/{size}"));
+        self.set_body(HttpBody::File(
+          file,
+          vec![FilePart::Range(start, end - start + 1)],
+        ));
+      }
+      _ => {
+        let boundary = multipart_boundary();
+        let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+        for (i, (start, end)) in ranges.into_iter().enumerate() {
+          parts.push(FilePart::Bytes(
+            format!(
+              "{}--{boundary}\r\n\
+              Content-Type: {}\r\n\
+              Content-Range: bytes {start}-{end}/{size}\r\n\r\n",
+              if i == 0 { "" } else { "\r\n" },
+              asset.content_type
+            )
+            .into_bytes(),
+          ));
+          parts.push(FilePart::Range(start, end - start + 1));
+        }
+        parts.push(FilePart::Bytes(
+          format!("\r\n--{boundary}--\r\n").into_bytes(),
+        ));
+
+        self.set_status(HttpStatus::PartialContent);
+        self.add_header(
+          "Content-Type",
+          &format!("multipart/byteranges; boundary={boundary}"),
+        );
+        self.set_body(HttpBody::File(file, parts));
+      }
+    }
+
     Ok(())
   }
 
+  /// Sends only the status line and headers, as a response to a HEAD request
+  pub fn omit_content(&mut self) {
+    self.omit_content = true;
+  }
+
   pub fn set_status(&mut self, status: HttpStatus) {
     self.status_code = status;
   }
@@ -106,17 +240,23 @@ impl HttpResponse {
   }
 
   pub fn send(&mut self, stream: &mut TcpStream) -> ServerResult {
-    if self.content.is_empty() {
+    if self.body.is_empty() {
       if let Some(e) = match self.status_code {
         HttpStatus::InternalServerError(ref e) => Some(e.to_string()),
-        HttpStatus::BadRequest(ref e) => Some(e.to_string()),
+        HttpStatus::BadRequest(ref e)
+        | HttpStatus::PayloadTooLarge(ref e)
+        | HttpStatus::UnsupportedMediaType(ref e)
+        | HttpStatus::NotImplemented(ref e)
+        | HttpStatus::RequestHeaderFieldsTooLarge(ref e) => Some(e.to_string()),
         _ => None,
       } {
         self.add_content(e.as_bytes());
       }
     }
     stream.write_all(self.raw().as_bytes())?;
-    stream.write_all(&self.content)?;
+    if !self.omit_content {
+      self.body.write_to(stream)?;
+    }
     stream.flush()?;
     Ok(())
   }
@@ -130,3 +270,33 @@ impl From<HttpStatus> for HttpResponse {
     }
   }
 }
+
+/// Whether the `If-Range` validator still matches the asset, a range request for
+/// a changed asset gets the full content instead (RFC 7233 section 3.2)
+fn if_range_matches(request: &HttpRequest, asset: &Asset, etag: &str) -> bool {
+  let Some(validator) = request.header("if-range") else {
+    return true;
+  };
+
+  // Entity tags are compared strongly, weak ones never match
+  if validator.starts_with('"') || validator.starts_with("W/") {
+    return validator == etag;
+  }
+
+  match (parse_http_date(validator), asset.identity.modified) {
+    (Some(date), Some(modified)) => http_date_secs(date) == http_date_secs(modified),
+    _ => false,
+  }
+}
+
+fn weak_tag(tag: &str) -> &str {
+  tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn multipart_boundary() -> String {
+  let nanos = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_nanos())
+    .unwrap_or(0);
+  format!("byteranges-{nanos:x}")
+}
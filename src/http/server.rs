@@ -1,51 +1,77 @@
 use super::*;
 use crate::ascii::LogDisplay;
 use crate::log;
-use std::io::prelude::*;
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::ops::Index;
-use std::sync::atomic::Ordering;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::{Index, IndexMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+/// Seconds a client is asked to wait before retrying when every worker is busy
+const RETRY_AFTER: u64 = 1;
+/// Queued connections allowed per worker before clients are turned away
+const QUEUE_SIZE_PER_WORKER: usize = 16;
+/// How long an idle connection is kept open waiting for the next request
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Server {
   listener: TcpListener,
   router: Arc<Router>,
+  workers: usize,
+  queue_size: usize,
 }
 
 impl Server {
-  pub fn new(addr: &str, router: Router) -> ServerResult<Self> {
+  /// Creates a server with `workers` threads and room for `queue_size` pending connections,
+  /// a value of 0 picks a default based on the available parallelism
+  pub fn new(addr: &str, router: Router, workers: usize, queue_size: usize) -> ServerResult<Self> {
+    let workers = match workers {
+      0 => thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4),
+      n => n,
+    };
+
     Ok(Self {
       listener: TcpListener::bind(addr)?,
       router: Arc::new(router),
+      workers,
+      queue_size: match queue_size {
+        0 => workers * QUEUE_SIZE_PER_WORKER,
+        n => n,
+      },
     })
   }
 
-  pub fn listen(&self) -> ServerResult {
-    if unsafe { SetConsoleCtrlHandler(Some(ctrl_handler), TRUE) } == FALSE {
-      return Err(ServerError::ExitHandler);
-    }
+  /// Accepts connections until an exit signal is received and returns the
+  /// status code the process should exit with
+  pub fn listen(&self) -> ServerResult<i32> {
+    set_exit_handler()?;
 
     let addr = self.listener.local_addr()?;
-    let mut connections = Vec::new();
+    // Half the workers can wait on idle keep-alive connections, the rest stay free for new clients
+    let keep_alive = Arc::new(KeepAliveSlots::new(self.workers / 2));
+    let mut pool = ThreadPool::new(
+      self.workers,
+      self.queue_size,
+      self.router.clone(),
+      keep_alive,
+    )?;
     self.listener.set_nonblocking(true)?;
 
-    log!(ok@"Server listening on {addr:?}");
-    while !CTRL_C_PRESSED.load(Ordering::SeqCst) {
+    log!(ok@"Server listening on {addr:?} with {} workers", pool.size());
+    while self.wait_for_client()? {
       match self.listener.accept() {
         Ok((stream, addr)) => {
-          let router = self.router.clone();
-          connections.push(
-            thread::Builder::new()
-              .name(addr.to_string())
-              .spawn(move || serve_client(stream, router))?,
-          );
-          connections.retain(|connection| !connection.is_finished());
-          log!(info@"Connections: {}", connections.len());
+          if let Err((stream, addr)) = pool.execute((stream, addr)) {
+            log!(warn@"[{addr}] Connection queue is full, rejecting");
+            reject_client(stream);
+          }
         }
         Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-          std::thread::sleep(std::time::Duration::from_millis(50));
+          #[cfg(windows)]
+          thread::sleep(Duration::from_millis(50));
         }
         Err(e) => {
           log!(err@"Error accepting connection\n{e}");
@@ -53,35 +79,178 @@ impl Server {
       }
     }
 
-    log!(info@"Ctrl C pressed, exiting...");
-    for connection in connections {
-      connection.join().expect("Could not join connection")?;
+    log!(info@"Exit signal received, waiting for workers...");
+    pool.join();
+
+    Ok(exit_code())
+  }
+
+  /// Blocks until a client is ready to be accepted, returns `false` once an exit signal is received
+  #[cfg(unix)]
+  fn wait_for_client(&self) -> ServerResult<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fds = [
+      libc::pollfd {
+        fd: self.listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+      },
+      libc::pollfd {
+        fd: exit_pipe_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+      },
+    ];
+
+    while !CTRL_C_PRESSED.load(Ordering::SeqCst) {
+      if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::Interrupted {
+          continue;
+        }
+        return Err(e.into());
+      }
+
+      if fds[1].revents != 0 {
+        break;
+      }
+      if fds[0].revents != 0 {
+        return Ok(true);
+      }
     }
 
-    Ok(())
+    Ok(false)
+  }
+
+  /// Windows has no self-pipe to wake up on, so this only checks the exit flag and
+  /// leaves the accept loop polling
+  #[cfg(windows)]
+  fn wait_for_client(&self) -> ServerResult<bool> {
+    Ok(!CTRL_C_PRESSED.load(Ordering::SeqCst))
+  }
+}
+
+fn reject_client(mut stream: TcpStream) {
+  let mut response = HttpResponse::from(HttpStatus::ServiceUnavailable);
+  response.add_header("Retry-After", &RETRY_AFTER.to_string());
+  response.add_header("Connection", "close");
+  if let Err(e) = response.send(&mut stream) {
+    log!(err@"Error rejecting connection: {e}");
+  }
+  stream.shutdown(Shutdown::Both).ok();
+}
+
+/// Caps how many workers can block on idle keep-alive connections, so idle clients
+/// cannot starve the connection queue
+pub struct KeepAliveSlots {
+  used: AtomicUsize,
+  max: usize,
+}
+
+impl KeepAliveSlots {
+  pub fn new(max: usize) -> Self {
+    Self {
+      used: AtomicUsize::new(0),
+      max,
+    }
+  }
+
+  /// Reserves the current worker for waiting on the connection's next request,
+  /// `None` if the connection should be closed instead
+  fn acquire(&self) -> Option<KeepAliveSlot<'_>> {
+    self
+      .used
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        (used < self.max).then_some(used + 1)
+      })
+      .ok()
+      .map(|_| KeepAliveSlot(self))
+  }
+}
+
+/// Released as soon as the next request arrives or the connection closes
+struct KeepAliveSlot<'a>(&'a KeepAliveSlots);
+
+impl Drop for KeepAliveSlot<'_> {
+  fn drop(&mut self) {
+    self.0.used.fetch_sub(1, Ordering::SeqCst);
   }
 }
 
-fn serve_client(mut stream: TcpStream, router: Arc<Router>) -> ServerResult {
-  stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-  let thread = thread::current();
-  let name = thread.name().unwrap_or("Unnamed Connection");
+pub(super) fn serve_client(
+  stream: TcpStream,
+  addr: SocketAddr,
+  router: Arc<Router>,
+  keep_alive_slots: &KeepAliveSlots,
+) -> ServerResult {
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+  let name = addr.to_string();
+  let mut connection = HttpConnection::new(stream);
 
   log!(ok@"[{name}] New connection");
 
-  let mut received_data = [0; 1024];
+  let mut keep_alive_slot = None;
   while !CTRL_C_PRESSED.load(Ordering::SeqCst) {
-    match stream.read(&mut received_data) {
-      Ok(size) if size > 0 => {
-        let mut response = router.route(HttpRequest::parse(&received_data));
-        response.send(&mut stream)?;
+    let request = match connection.read_request() {
+      Ok(Some(request)) => request,
+      Ok(None) => break,
+      Err(e) => {
+        if !matches!(
+          e.kind(),
+          std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ) {
+          log!(warn@"[{name}] Error reading request: {e}");
+        }
+        break;
+      }
+    };
+
+    // Bodies are only buffered for requests a route takes, the rest are consumed and dropped
+    let request = match request {
+      Ok(mut request) => {
+        let keep = router.takes(&request);
+        match connection.read_body(&mut request, keep) {
+          Ok(body) => body.map(|_| request),
+          Err(e) => {
+            log!(warn@"[{name}] Error reading request body: {e}");
+            break;
+          }
+        }
+      }
+      Err(e) => Err(e),
+    };
+
+    drop(keep_alive_slot.take());
+
+    // A request that failed to parse leaves the stream in an unknown state
+    keep_alive_slot = match request {
+      Ok(ref r) if r.keep_alive() && !CTRL_C_PRESSED.load(Ordering::SeqCst) => {
+        keep_alive_slots.acquire()
       }
-      Ok(_) => break,
-      Err(_) => break,
+      _ => None,
+    };
+    let keep_alive = keep_alive_slot.is_some();
+
+    let mut response = router.route(request);
+    if keep_alive {
+      response.add_header("Connection", "keep-alive");
+      response.add_header(
+        "Keep-Alive",
+        &format!("timeout={}", KEEP_ALIVE_TIMEOUT.as_secs()),
+      );
+    } else {
+      response.add_header("Connection", "close");
+    }
+    response.send(&mut connection)?;
+
+    if !keep_alive {
+      break;
     }
   }
 
-  if let Err(e) = stream.shutdown(Shutdown::Both) {
+  if let Err(e) = connection.shutdown(Shutdown::Both) {
     log!(err@"[{name}] Error closing stream: {e}");
   }
 
@@ -90,50 +259,224 @@ fn serve_client(mut stream: TcpStream, router: Arc<Router>) -> ServerResult {
 }
 
 type Route = Box<dyn Fn(&HttpRequest) -> ServerResult<HttpResponse> + Send + Sync>;
+
+#[derive(Default)]
 pub struct Router {
   get: Vec<(String, Route)>,
+  head: Vec<(String, Route)>,
+  post: Vec<(String, Route)>,
+  put: Vec<(String, Route)>,
+  delete: Vec<(String, Route)>,
+  options: Vec<(String, Route)>,
+  cache_control: Vec<(String, String)>,
 }
 
 impl Router {
   pub fn new() -> Self {
-    Self { get: Vec::new() }
+    Self::default()
   }
 
+  /// Registers a GET route, which also answers HEAD requests unless a HEAD route
+  /// is registered for the same endpoint
   pub fn get(
     &mut self,
     endpoint: &str,
     route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
   ) -> &mut Self {
-    self.get.push((endpoint.to_string(), Box::new(route)));
+    self.add(HttpMethod::Get, endpoint, route)
+  }
+
+  /// Sets the `Cache-Control` header of successful responses to requests matching `endpoint`,
+  /// the first matching endpoint is used
+  pub fn cache_control(&mut self, endpoint: &str, value: &str) -> &mut Self {
+    self
+      .cache_control
+      .push((endpoint.to_string(), value.to_string()));
+    self
+  }
+
+  fn add(
+    &mut self,
+    method: HttpMethod,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self[method].push((endpoint.to_string(), Box::new(route)));
     self
   }
 
   fn route(&self, request: HttpRequestResult) -> HttpResponse {
-    let request = match request {
+    let mut request = match request {
       Ok(r) => r,
+      Err(e @ HttpRequestError::HeaderTooLarge(..)) => {
+        return HttpStatus::RequestHeaderFieldsTooLarge(e).into();
+      }
+      Err(e @ HttpRequestError::BodyTooLarge(..)) => {
+        return HttpStatus::PayloadTooLarge(e).into();
+      }
+      // Methods outside of `HttpMethod` are well formed, just not supported
+      Err(e @ HttpRequestError::Method(..)) => {
+        return HttpStatus::NotImplemented(e).into();
+      }
       Err(e) => {
         return HttpStatus::BadRequest(e).into();
       }
     };
 
-    self[request.method]
-      .iter()
-      .find(|ep| {
-        if let Some(path) = ep.0.strip_suffix("/*") {
-          request.path.starts_with(path)
-        } else {
-          request.path == ep.0
-        }
-      })
-      .map(|ep| ep.1(&request))
-      .unwrap_or(Ok(HttpStatus::NotFound.into()))
-      .unwrap_or_else(|e| {
-        match e {
-          ServerError::BadRequest(e) => HttpStatus::BadRequest(e),
-          _ => HttpStatus::InternalServerError(e),
+    let (route, params) = match self.find_request(&request) {
+      Ok(Some(found)) => found,
+      Ok(None) => return self.unrouted(&request),
+      Err(e) => return HttpStatus::BadRequest(e).into(),
+    };
+    request.params = params;
+
+    let mut response = route(&request).unwrap_or_else(|e| {
+      match e {
+        ServerError::BadRequest(HttpRequestError::Forbidden(..)) => HttpStatus::Forbidden,
+        ServerError::BadRequest(HttpRequestError::NotFound(..)) => HttpStatus::NotFound,
+        ServerError::BadRequest(e @ HttpRequestError::UnsupportedMedia(..)) => {
+          HttpStatus::UnsupportedMediaType(e)
         }
-        .into()
-      })
+        ServerError::BadRequest(e) => HttpStatus::BadRequest(e),
+        _ => HttpStatus::InternalServerError(e),
+      }
+      .into()
+    });
+
+    let cacheable = matches!(
+      response.status(),
+      HttpStatus::OK | HttpStatus::PartialContent | HttpStatus::NotModified
+    );
+    if cacheable {
+      if let Some((_, value)) = self
+        .cache_control
+        .iter()
+        .find(|(endpoint, _)| matches!(match_route(endpoint, &request.raw_path), Ok(Some(_))))
+      {
+        response.add_header("Cache-Control", value);
+      }
+    }
+
+    if request.method == HttpMethod::Head {
+      response.omit_content();
+    }
+
+    response
+  }
+
+  /// Whether a route takes `request`, only then is its body worth reading
+  fn takes(&self, request: &HttpRequest) -> bool {
+    matches!(self.find_request(request), Ok(Some(_)))
+  }
+
+  /// Route for the request's method and path, HEAD requests fall back to GET routes
+  fn find_request(&self, request: &HttpRequest) -> HttpRequestResult<Option<(&Route, PathParams)>> {
+    match self.find(request.method, &request.raw_path)? {
+      None if request.method == HttpMethod::Head => self.find(HttpMethod::Get, &request.raw_path),
+      found => Ok(found),
+    }
+  }
+
+  /// Response for a request with no route for its method, 405 if the path
+  /// is routed for other methods, 404 otherwise
+  fn unrouted(&self, request: &HttpRequest) -> HttpResponse {
+    let allowed = self.allowed_methods(&request.raw_path);
+    if allowed.is_empty() {
+      return HttpStatus::NotFound.into();
+    }
+
+    let allow = allowed
+      .iter()
+      .map(HttpMethod::as_str)
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    let mut response: HttpResponse = if request.method == HttpMethod::Options {
+      HttpStatus::NoContent.into()
+    } else {
+      HttpStatus::MethodNotAllowed.into()
+    };
+    response.add_header("Allow", &allow);
+    response
+  }
+
+  fn allowed_methods(&self, path: &str) -> Vec<HttpMethod> {
+    let mut allowed = HttpMethod::ALL
+      .into_iter()
+      .filter(|method| matches!(self.find(*method, path), Ok(Some(_))))
+      .collect::<Vec<_>>();
+
+    if allowed.is_empty() {
+      return allowed;
+    }
+
+    if allowed.contains(&HttpMethod::Get) && !allowed.contains(&HttpMethod::Head) {
+      allowed.insert(1, HttpMethod::Head);
+    }
+    if !allowed.contains(&HttpMethod::Options) {
+      allowed.push(HttpMethod::Options);
+    }
+
+    allowed
+  }
+
+  fn find(
+    &self,
+    method: HttpMethod,
+    path: &str,
+  ) -> HttpRequestResult<Option<(&Route, PathParams)>> {
+    for (endpoint, route) in &self[method] {
+      if let Some(params) = match_route(endpoint, path)? {
+        return Ok(Some((route, params)));
+      }
+    }
+    Ok(None)
+  }
+}
+
+// Every route served so far is a GET, these complete the registration API
+#[allow(dead_code)]
+impl Router {
+  pub fn head(
+    &mut self,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self.add(HttpMethod::Head, endpoint, route)
+  }
+
+  pub fn post(
+    &mut self,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self.add(HttpMethod::Post, endpoint, route)
+  }
+
+  pub fn put(
+    &mut self,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self.add(HttpMethod::Put, endpoint, route)
+  }
+
+  pub fn delete(
+    &mut self,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self.add(HttpMethod::Delete, endpoint, route)
+  }
+
+  /// Registers an OPTIONS route, without one OPTIONS requests are answered
+  /// with the allowed methods for the endpoint
+  pub fn options(
+    &mut self,
+    endpoint: &str,
+    route: impl Fn(&HttpRequest) -> ServerResult<HttpResponse> + 'static + Send + Sync,
+  ) -> &mut Self {
+    self.add(HttpMethod::Options, endpoint, route)
   }
 }
 
@@ -142,6 +485,24 @@ impl Index<HttpMethod> for Router {
   fn index(&self, index: HttpMethod) -> &Self::Output {
     match index {
       HttpMethod::Get => &self.get,
+      HttpMethod::Head => &self.head,
+      HttpMethod::Post => &self.post,
+      HttpMethod::Put => &self.put,
+      HttpMethod::Delete => &self.delete,
+      HttpMethod::Options => &self.options,
+    }
+  }
+}
+
+impl IndexMut<HttpMethod> for Router {
+  fn index_mut(&mut self, index: HttpMethod) -> &mut Self::Output {
+    match index {
+      HttpMethod::Get => &mut self.get,
+      HttpMethod::Head => &mut self.head,
+      HttpMethod::Post => &mut self.post,
+      HttpMethod::Put => &mut self.put,
+      HttpMethod::Delete => &mut self.delete,
+      HttpMethod::Options => &mut self.options,
     }
   }
 }
@@ -0,0 +1,168 @@
+use crate::rumpeg::{AVFrame, AVImageEncoder, RumpegResult};
+use crate::webp::{WebPImage, WebPOptions};
+use std::ops::Deref;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+  #[error("Unknown image format \"{0}\"")]
+  UnknownFormat(String),
+}
+
+/// File formats frames can be encoded as
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImageFormat {
+  // Declared in order of preference, for clients accepting several of them equally
+  #[default]
+  WebP,
+  Avif,
+  Jpeg,
+  Png,
+}
+
+impl ImageFormat {
+  pub fn mime_type(&self) -> &'static str {
+    match self {
+      Self::WebP => "image/webp",
+      Self::Avif => "image/avif",
+      Self::Jpeg => "image/jpeg",
+      Self::Png => "image/png",
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::WebP => "webp",
+      Self::Avif => "avif",
+      Self::Jpeg => "jpg",
+      Self::Png => "png",
+    }
+  }
+
+  pub fn from_extension(path: &str) -> Option<Self> {
+    Path::new(path).extension()?.to_str()?.parse().ok()
+  }
+
+  /// Whether this FFmpeg build can encode the format, AVIF needs one of the AV1 encoders
+  pub fn has_encoder(&self) -> bool {
+    match self {
+      Self::WebP => true,
+      format => AVImageEncoder::supports(*format),
+    }
+  }
+
+  /// Picks the supported format with the highest weight in an `Accept` header, wildcards
+  /// are left to the default. Formats without an encoder are skipped
+  pub fn from_accept(accept: &str) -> Option<Self> {
+    let mut best: Option<(Self, f32)> = None;
+    for range in accept.split(',') {
+      let mut params = range.split(';');
+      let Some(format) = params
+        .next()
+        .and_then(|mime| Self::from_mime_type(mime.trim()))
+        .filter(Self::has_encoder)
+      else {
+        continue;
+      };
+      let weight = params
+        .find_map(|param| param.trim().strip_prefix("q="))
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(1.);
+
+      let preferred = match best {
+        Some((best, best_weight)) => {
+          weight > best_weight || (weight == best_weight && format < best)
+        }
+        None => weight > 0.,
+      };
+      if preferred {
+        best = Some((format, weight));
+      }
+    }
+    best.map(|(format, _)| format)
+  }
+
+  fn from_mime_type(mime_type: &str) -> Option<Self> {
+    match mime_type.to_lowercase().as_str() {
+      "image/webp" => Some(Self::WebP),
+      "image/avif" => Some(Self::Avif),
+      "image/jpeg" | "image/jpg" => Some(Self::Jpeg),
+      "image/png" => Some(Self::Png),
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for ImageFormat {
+  type Err = ImageError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "webp" => Ok(Self::WebP),
+      "avif" => Ok(Self::Avif),
+      "jpg" | "jpeg" => Ok(Self::Jpeg),
+      "png" => Ok(Self::Png),
+      s => Err(ImageError::UnknownFormat(s.to_string())),
+    }
+  }
+}
+
+/// An encoded image, in whichever buffer its encoder produced it
+#[derive(Debug)]
+pub enum EncodedImage {
+  WebP(WebPImage),
+  Bytes(Vec<u8>),
+}
+
+impl Deref for EncodedImage {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    match self {
+      Self::WebP(image) => image,
+      Self::Bytes(bytes) => bytes,
+    }
+  }
+}
+
+impl AsRef<[u8]> for EncodedImage {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+/// Turns frames into image files of a single format
+pub trait ImageEncoder {
+  fn encode(&self, frame: &AVFrame) -> RumpegResult<EncodedImage>;
+}
+
+/// Output format along with the encoder settings, the WebP quality is shared by the other
+/// lossy formats
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ImageOptions {
+  pub format: ImageFormat,
+  pub webp: WebPOptions,
+}
+
+impl ImageOptions {
+  /// Format and the settings it is encoded with, the ones the format ignores are left out
+  pub fn cache_key(&self) -> String {
+    match self.format {
+      ImageFormat::WebP => format!("webp {}", self.webp.cache_key()),
+      ImageFormat::Avif | ImageFormat::Jpeg => format!(
+        "{} q={}",
+        self.format.extension(),
+        self.webp.quality.clamp(0., 100.)
+      ),
+      ImageFormat::Png => "png".to_string(),
+    }
+  }
+
+  pub fn encoder(&self) -> Box<dyn ImageEncoder> {
+    match self.format {
+      ImageFormat::WebP => Box::new(self.webp),
+      format => Box::new(AVImageEncoder::new(format, self.webp.quality)),
+    }
+  }
+}
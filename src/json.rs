@@ -0,0 +1,161 @@
+use crate::ffmpeg;
+use std::fmt::Write;
+
+/// Values that can be written out as JSON
+pub trait ToJson {
+  fn write_json(&self, out: &mut String);
+
+  fn to_json(&self) -> String {
+    let mut out = String::new();
+    self.write_json(&mut out);
+    out
+  }
+}
+
+/// Builds a JSON object field by field, in insertion order
+#[derive(Debug)]
+pub struct JsonObject {
+  buffer: String,
+}
+
+impl JsonObject {
+  pub fn new() -> Self {
+    Self {
+      buffer: String::from("{"),
+    }
+  }
+
+  pub fn field<T: ToJson + ?Sized>(mut self, key: &str, value: &T) -> Self {
+    if self.buffer.len() > 1 {
+      self.buffer.push(',');
+    }
+    key.write_json(&mut self.buffer);
+    self.buffer.push(':');
+    value.write_json(&mut self.buffer);
+    self
+  }
+
+  pub fn finish(mut self) -> String {
+    self.buffer.push('}');
+    self.buffer
+  }
+}
+
+impl Default for JsonObject {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ToJson for JsonObject {
+  fn write_json(&self, out: &mut String) {
+    out.push_str(&self.buffer);
+    out.push('}');
+  }
+}
+
+impl ToJson for str {
+  fn write_json(&self, out: &mut String) {
+    out.push('"');
+    for c in self.chars() {
+      match c {
+        '"' => out.push_str("\\\""),
+        '\\' => out.push_str("\\\\"),
+        '\n' => out.push_str("\\n"),
+        '\r' => out.push_str("\\r"),
+        '\t' => out.push_str("\\t"),
+        c if c.is_control() => {
+          write!(out, "\\u{:04x}", c as u32).ok();
+        }
+        c => out.push(c),
+      }
+    }
+    out.push('"');
+  }
+}
+
+impl ToJson for String {
+  fn write_json(&self, out: &mut String) {
+    self.as_str().write_json(out)
+  }
+}
+
+impl ToJson for bool {
+  fn write_json(&self, out: &mut String) {
+    out.push_str(if *self { "true" } else { "false" });
+  }
+}
+
+macro_rules! impl_to_json_int {
+  ($($t: ty),*) => {
+    $(
+      impl ToJson for $t {
+        fn write_json(&self, out: &mut String) {
+          write!(out, "{self}").ok();
+        }
+      }
+    )*
+  };
+}
+
+impl_to_json_int!(i32, i64, u32, u64, usize);
+
+macro_rules! impl_to_json_float {
+  ($($t: ty),*) => {
+    $(
+      impl ToJson for $t {
+        fn write_json(&self, out: &mut String) {
+          // JSON has no representation for NaN or infinity
+          if self.is_finite() {
+            write!(out, "{self}").ok();
+          } else {
+            out.push_str("null");
+          }
+        }
+      }
+    )*
+  };
+}
+
+impl_to_json_float!(f32, f64);
+
+impl<T: ToJson> ToJson for Option<T> {
+  fn write_json(&self, out: &mut String) {
+    match self {
+      Some(value) => value.write_json(out),
+      None => out.push_str("null"),
+    }
+  }
+}
+
+impl<T: ToJson> ToJson for [T] {
+  fn write_json(&self, out: &mut String) {
+    out.push('[');
+    for (i, value) in self.iter().enumerate() {
+      if i > 0 {
+        out.push(',');
+      }
+      value.write_json(out);
+    }
+    out.push(']');
+  }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+  fn write_json(&self, out: &mut String) {
+    self.as_slice().write_json(out)
+  }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+  fn write_json(&self, out: &mut String) {
+    (**self).write_json(out)
+  }
+}
+
+/// Rationals are written as `[num, den]`
+impl ToJson for ffmpeg::AVRational {
+  fn write_json(&self, out: &mut String) {
+    [self.num, self.den][..].write_json(out)
+  }
+}
@@ -1,21 +1,34 @@
 mod ascii;
+mod audio;
+mod cache;
 mod cli;
 mod ffmpeg;
 mod http;
+mod image;
+mod json;
 mod math;
 mod routes;
 mod rumpeg;
 mod video;
+mod video_pool;
 mod webp;
 
 use crate::cli::CLIArgs;
-use crate::http::{Router, Server};
+use crate::http::{Router, Server, SymlinkPolicy};
 use ascii::LogDisplay;
+use audio::{Audio, AudioImageOptions};
+use cache::ThumbnailCache;
+use image::{ImageFormat, ImageOptions};
+use json::ToJson;
 use rumpeg::*;
 use std::fs::write;
+use std::path::Path;
 use std::sync::atomic::{AtomicPtr, Ordering};
-use std::time::Instant;
-use video::Video;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use video::{Video, VideoOptions};
+use video_pool::VideoPool;
+use webp::WebPOptions;
 
 macro_rules! unwrap {
   (Some $wrapped: expr, Err $( $err: expr ),*) => {
@@ -40,31 +53,102 @@ macro_rules! unwrap {
 }
 
 pub static MEDIA_FOLDER: AtomicPtr<String> = AtomicPtr::new(std::ptr::null_mut());
+pub static SYMLINK_POLICY: OnceLock<SymlinkPolicy> = OnceLock::new();
+pub static THUMBNAIL_CACHE: OnceLock<ThumbnailCache> = OnceLock::new();
+pub static VIDEO_POOL: OnceLock<VideoPool> = OnceLock::new();
+/// Decoder options from the command line, query args take precedence over them
+pub static DECODER_OPTIONS: OnceLock<DecoderOptions> = OnceLock::new();
+/// Encoder options from the command line, query args take precedence over them
+pub static WEBP_OPTIONS: OnceLock<WebPOptions> = OnceLock::new();
 
 fn main() {
-  log!(ok@"Using Ffmpeg v{} and libwebp v{}", rumpeg::version(), webp::version());
   let mut args = unwrap!(Ok CLIArgs::read(), Err "Error");
+  // Keeps stdout parseable when printing JSON
+  if !args.json {
+    log!(ok@"Using Ffmpeg v{} and libwebp v{}", rumpeg::version(), webp::version());
+  }
   rumpeg::set_log_level(args.log_level);
 
   if args.host {
     let mut router = Router::new();
     router
-      .get("/frame/*", routes::get_frame)
-      .get("/media/*", routes::get_asset)
+      .get("/frame/*path", routes::get_frame)
+      .get("/preview/*path", routes::get_preview)
+      .get("/info/*path", routes::get_info)
+      .get("/waveform/*path", routes::get_waveform)
+      .get("/media/*path", routes::get_asset)
       .get("/favicon.ico", routes::favicon)
-      .get("/*", routes::index);
-    let server = unwrap!(Ok Server::new("0.0.0.0:8080", router), Err "Could not create server");
+      .get("/cache", routes::cache_stats)
+      .get("/*", routes::index)
+      .cache_control("/frame/*path", &args.frame_cache_control)
+      .cache_control("/preview/*path", &args.frame_cache_control)
+      .cache_control("/waveform/*path", &args.frame_cache_control)
+      .cache_control("/media/*path", &args.media_cache_control);
+    let server = unwrap!(
+      Ok Server::new("0.0.0.0:8080", router, args.workers, args.queue_size),
+      Err "Could not create server"
+    );
     MEDIA_FOLDER.store(&mut args.filepath as *mut _, Ordering::SeqCst);
+    SYMLINK_POLICY.get_or_init(|| args.symlink_policy);
+    DECODER_OPTIONS.get_or_init(|| args.decoder);
+    WEBP_OPTIONS.get_or_init(|| args.webp);
 
-    unwrap!(Ok server.listen(), Err "Server could not listen");
+    if !args.no_cache {
+      let cache = unwrap!(
+        Ok ThumbnailCache::open(&args.cache_dir, args.cache_size * 1024 * 1024),
+        Err "Could not open thumbnail cache"
+      );
+      THUMBNAIL_CACHE.get_or_init(|| cache);
+    }
+
+    let video_pool = VIDEO_POOL.get_or_init(|| {
+      VideoPool::new(
+        args.video_pool_size,
+        Duration::from_secs(args.video_idle_timeout),
+      )
+    });
+    if let Err(e) = video_pool.spawn_sweeper() {
+      log!(warn@"Idle videos are only closed on the next request, could not start sweeper\n{e}");
+    }
+
+    let code = unwrap!(Ok server.listen(), Err "Server could not listen");
+    log!(info@"Server stopped (exit code {code})");
+    std::process::exit(code);
+  }
+
+  // The extension of the output picks the image format, every file is named after its stem
+  let image_options = ImageOptions {
+    format: ImageFormat::from_extension(&args.output).unwrap_or_default(),
+    webp: args.webp,
+  };
+  let output = Path::new(&args.output).with_extension("");
+  let output = output.to_string_lossy();
+
+  if args.waveform {
+    let start_time = Instant::now();
+    unwrap!(
+      Ok save_waveform(&args, &output, &image_options),
+      Err "Failed to save waveform"
+    );
+    log!(ok@"Done in {:?}", Instant::now() - start_time);
     return;
   }
 
   let video = unwrap!(
-    Ok Video::open(&args.filepath, args.width, args.height),
+    Ok Video::open(&args.filepath, VideoOptions {
+      stream: args.stream,
+      decoder: args.decoder,
+      width: args.width,
+      height: args.height,
+    }),
     Err "Failed to open video"
   );
 
+  if args.json {
+    println!("{}", video.to_json());
+    return;
+  }
+
   if args.debug {
     println!("{}", video);
   }
@@ -72,33 +156,91 @@ fn main() {
   let start_time = Instant::now();
 
   unwrap!(
-    Ok save_image(&video, "temp/image", args.seek_position),
+    Ok save_image(&video, &output, args.seek_position, args.seek_mode, &image_options),
     Err "Failed to save image"
   );
 
-  if args.film {
+  if args.film && !video.is_attached_picture() {
     unwrap!(
-      Ok save_film_strip(&video, "temp/image", args.seek_position, args.end, args.step),
+      Ok save_film_strip(
+        &video,
+        &output,
+        args.seek_position,
+        args.end,
+        args.step,
+        args.seek_mode,
+        &image_options
+      ),
       Err "Failed to save film roll"
     );
   }
 
+  if args.preview && !video.is_attached_picture() {
+    unwrap!(
+      Ok save_preview(&video, &args, &output),
+      Err "Failed to save preview"
+    );
+  }
+
   let end_time = Instant::now();
 
   log!(ok@"Done in {:?}", end_time - start_time)
 }
 
+fn save_waveform(
+  args: &CLIArgs,
+  thumbnail_path: &str,
+  image_options: &ImageOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let audio = Audio::open(&args.filepath)?;
+  let options = AudioImageOptions {
+    mode: args.audio_image_mode,
+    width: args.width,
+    height: args.height,
+    ..Default::default()
+  };
+  write(
+    format!(
+      "{thumbnail_path}-waveform.{}",
+      image_options.format.extension()
+    ),
+    audio.render(options)?.encode_as(image_options)?,
+  )?;
+  Ok(())
+}
+
+fn save_preview(
+  video: &Video,
+  args: &CLIArgs,
+  thumbnail_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let preview = video.animated_preview(
+    args.seek_position,
+    args.end,
+    args.step,
+    args.seek_mode,
+    &args.preview_options,
+    &args.webp,
+  )?;
+  write(format!("{thumbnail_path}-preview.webp"), preview)?;
+  Ok(())
+}
+
 fn save_film_strip(
   video: &Video,
   thumbnail_path: &str,
   start: SeekPosition,
   end: SeekPosition,
   step: SeekPosition,
+  mode: SeekMode,
+  image_options: &ImageOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
+  let film_strip = video.film_strip(start, end, step, mode)?;
   write(
-    format!("{thumbnail_path}-film.webp"),
-    video.film_strip(start, end, step)?.encode_as_webp()?,
+    format!("{thumbnail_path}-film.{}", image_options.format.extension()),
+    film_strip.frame.encode_as(image_options)?,
   )?;
+  log!(info@"Film strip tiles taken at {:?} ms", film_strip.timestamps_ms);
 
   Ok(())
 }
@@ -107,17 +249,25 @@ fn save_image(
   video: &Video,
   thumbnail_path: &str,
   position: SeekPosition,
+  mode: SeekMode,
+  image_options: &ImageOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-  if let Some(mut frame) = video
+  let filepath = format!("{thumbnail_path}.{}", image_options.format.extension());
+  if video.is_attached_picture() {
+    let image = video.frame_to_image(&video.attached_picture()?, image_options)?;
+    write(filepath, image)?;
+  } else if let Some(frame) = video
     .frames(
       position,
       SeekPosition::Percentage(1.),
       SeekPosition::default(),
+      mode,
     )?
     .next()
   {
-    let image = video.frame_to_webp(&mut frame)?;
-    write(format!("{thumbnail_path}.webp"), image)?;
+    log!(info@"Frame taken at {} ms", video.timestamp_millis(&frame));
+    let image = video.frame_to_image(&frame, image_options)?;
+    write(filepath, image)?;
   }
 
   Ok(())
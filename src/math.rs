@@ -103,6 +103,116 @@ impl Deref for Matrix3x3 {
   }
 }
 
+/// In-place iterative radix-2 Cooley-Tukey FFT, `re` and `im` must have the
+/// same power of two length
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+  let n = re.len();
+  debug_assert!(n.is_power_of_two() && im.len() == n);
+
+  let mut j = 0;
+  for i in 1..n {
+    let mut bit = n >> 1;
+    while j & bit != 0 {
+      j ^= bit;
+      bit >>= 1;
+    }
+    j |= bit;
+    if i < j {
+      re.swap(i, j);
+      im.swap(i, j);
+    }
+  }
+
+  let mut len = 2;
+  while len <= n {
+    let angle = -2. * std::f32::consts::PI / len as f32;
+    for start in (0..n).step_by(len) {
+      for k in 0..len / 2 {
+        let (sin, cos) = (angle * k as f32).sin_cos();
+        let (a, b) = (start + k, start + k + len / 2);
+        let t_re = re[b] * cos - im[b] * sin;
+        let t_im = re[b] * sin + im[b] * cos;
+        re[b] = re[a] - t_re;
+        im[b] = im[a] - t_im;
+        re[a] += t_re;
+        im[a] += t_im;
+      }
+    }
+    len <<= 1;
+  }
+}
+
 fn fixed_point_to_f32(x: f32, n: i32) -> f32 {
   x / (1 << n) as f32
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f32::consts::PI;
+
+  const N: usize = 64;
+
+  fn spectrum(signal: impl Fn(f32) -> f32) -> Vec<f32> {
+    let mut re = (0..N).map(|i| signal(i as f32)).collect::<Vec<_>>();
+    let mut im = vec![0.; N];
+    fft(&mut re, &mut im);
+    re.iter().zip(&im).map(|(re, im)| re.hypot(*im)).collect()
+  }
+
+  fn assert_peaks(magnitudes: &[f32], peaks: &[(usize, f32)]) {
+    for (bin, magnitude) in magnitudes.iter().enumerate() {
+      let expected = peaks
+        .iter()
+        .find(|(peak, _)| *peak == bin)
+        .map_or(0., |(_, magnitude)| *magnitude);
+      assert!(
+        (magnitude - expected).abs() < 1e-3,
+        "Bin {bin} is {magnitude}, expected {expected}"
+      );
+    }
+  }
+
+  #[test]
+  fn constant_is_dc_only() {
+    assert_peaks(&spectrum(|_| 1.), &[(0, N as f32)]);
+  }
+
+  #[test]
+  fn sine_peaks_at_its_frequency() {
+    let half = N as f32 / 2.;
+    for k in [1, 5, N / 4, N / 2 - 1] {
+      let sine = spectrum(|i| (2. * PI * k as f32 * i / N as f32).sin());
+      assert_peaks(&sine, &[(k, half), (N - k, half)]);
+    }
+  }
+
+  #[test]
+  fn sines_add_up() {
+    let mixed = spectrum(|i| {
+      (2. * PI * 3. * i / N as f32).sin() + 0.5 * (2. * PI * 10. * i / N as f32).cos()
+    });
+    let half = N as f32 / 2.;
+    assert_peaks(
+      &mixed,
+      &[
+        (3, half),
+        (N - 3, half),
+        (10, half / 2.),
+        (N - 10, half / 2.),
+      ],
+    );
+  }
+
+  #[test]
+  fn cosine_phase_is_real() {
+    let k = 4;
+    let mut re = (0..N)
+      .map(|i| (2. * PI * k as f32 * i as f32 / N as f32).cos())
+      .collect::<Vec<_>>();
+    let mut im = vec![0.; N];
+    fft(&mut re, &mut im);
+    assert!((re[k] - N as f32 / 2.).abs() < 1e-3);
+    assert!(im[k].abs() < 1e-3);
+  }
+}
@@ -1,12 +1,26 @@
+use crate::ascii::LogDisplay;
+use crate::audio::{Audio, AudioError, AudioImageOptions};
+use crate::cache::{CacheKey, CachedImage};
+use crate::ffmpeg;
 use crate::http::{
-  find_query_arg, find_query_flag, FromPath, FromQueryString, HttpRequest, HttpRequestError,
-  HttpRequestResult, HttpResponse, HttpStatus, ServerResult,
+  find_query_arg, find_query_arg_or, find_query_flag, find_query_flag_or, parse_query,
+  resolve_path, try_find_query_arg, AssetError, FileIdentity, FromPath, FromQueryString, HttpBody,
+  HttpRequest, HttpRequestError, HttpRequestResult, HttpResponse, HttpStatus, PathParams,
+  QueryParams, ServerResult,
+};
+use crate::image::{EncodedImage, ImageFormat, ImageOptions};
+use crate::json::{JsonObject, ToJson};
+use crate::rumpeg::{DecoderOptions, RumpegError, SeekMode, SeekPosition, StreamSelector};
+use crate::video::{PreviewOptions, Video, VideoError, VideoOptions, MAX_PREVIEW_FRAMES};
+use crate::video_pool::VideoHandle;
+use crate::webp::WebPOptions;
+use crate::{from_path, log};
+use crate::{
+  DECODER_OPTIONS, MEDIA_FOLDER, SYMLINK_POLICY, THUMBNAIL_CACHE, VIDEO_POOL, WEBP_OPTIONS,
 };
-use crate::rumpeg::SeekPosition;
-use crate::video::Video;
-use crate::MEDIA_FOLDER;
 use std::ops::Deref;
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
 pub fn index(request: &HttpRequest) -> ServerResult<HttpResponse> {
   HttpResponse::from_asset("public/index.html", request)
@@ -19,35 +33,279 @@ pub fn favicon(request: &HttpRequest) -> ServerResult<HttpResponse> {
 pub fn get_frame(request: &HttpRequest) -> ServerResult<HttpResponse> {
   let query: VideoArgs = request.query()?;
   let videopath: FilePath = request.path()?;
+  let image_options = image_options(request, query.format, query.webp);
+  let variant = format!("{} {}", query.cache_key(), image_options.cache_key());
 
-  let Ok(video) = Video::open(
-    &videopath,
-    query.width,
-    query.height,
-  ) else {
-    return Ok(HttpStatus::NotFound.into());
-  };
+  let mut response = HttpResponse::default();
+  response.add_header("Vary", "Accept");
+  cached_image(request, response, &videopath, &variant, || {
+    let handle = open_video(&videopath, &query)?;
+    let video = handle.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut timestamps_ms = Vec::new();
+    // Cover art is a single picture, there is nothing to seek or tile
+    let image = if video.is_attached_picture() {
+      video.frame_to_image(&video.attached_picture()?, &image_options)?
+    } else if query.film {
+      let film_strip =
+        video.film_strip(query.seek_position, query.end, query.step, query.seek_mode)?;
+      timestamps_ms = film_strip.timestamps_ms;
+      film_strip.frame.encode_as(&image_options)?
+    } else {
+      let Some(frame) = video
+        .frames(query.seek_position, query.end, query.step, query.seek_mode)?
+        .next()
+      else {
+        return Ok(None);
+      };
+      timestamps_ms.push(video.timestamp_millis(&frame));
+      video.frame_to_image(&frame, &image_options)?
+    };
+
+    let mut headers = vec![
+      (
+        "Content-Type".to_string(),
+        image_options.format.mime_type().to_string(),
+      ),
+      ("X-Video-Width".to_string(), video.width.to_string()),
+      ("X-Video-Height".to_string(), video.height.to_string()),
+      (
+        "X-Video-Duration".to_string(),
+        video.duration_ms.to_string(),
+      ),
+      (
+        "X-Video-Extensions".to_string(),
+        video.extensions.to_string(),
+      ),
+    ];
+    if !timestamps_ms.is_empty() {
+      let timestamps: Vec<_> = timestamps_ms.iter().map(|t| t.to_string()).collect();
+      headers.push(("X-Frame-Timestamps".to_string(), timestamps.join(",")));
+    }
+
+    Ok(Some(CachedImage {
+      headers,
+      data: image.to_vec(),
+    }))
+  })
+}
+
+pub fn get_preview(request: &HttpRequest) -> ServerResult<HttpResponse> {
+  let query: PreviewArgs = request.query()?;
+  let videopath: FilePath = request.path()?;
+
+  let response = HttpResponse::default();
+  cached_image(request, response, &videopath, &query.cache_key(), || {
+    let handle = open_video(&videopath, &query.video)?;
+    let video = handle.lock().unwrap_or_else(|e| e.into_inner());
+    let args = &query.video;
 
-  let image = if query.film {
-    video
-      .film_strip(query.seek_position, query.end, query.step)?
-      .encode_as_webp()?
-  } else {
-    let Some(mut frame) = video
-      .frames(query.seek_position, query.end, query.step)?
-      .next() else {
-        return Ok(HttpStatus::NotFound.into())
+    // Cover art does not move, so its preview is the picture itself
+    let image = if video.is_attached_picture() {
+      let still = ImageOptions {
+        format: ImageFormat::WebP,
+        webp: args.webp,
       };
-    video.frame_to_webp(&mut frame)?
+      video.frame_to_image(&video.attached_picture()?, &still)?
+    } else {
+      EncodedImage::WebP(video.animated_preview(
+        args.seek_position,
+        args.end,
+        args.step,
+        args.seek_mode,
+        &query.preview,
+        &args.webp,
+      )?)
+    };
+
+    Ok(Some(CachedImage {
+      headers: vec![
+        ("Content-Type".to_string(), "image/webp".to_string()),
+        ("X-Video-Width".to_string(), video.width.to_string()),
+        ("X-Video-Height".to_string(), video.height.to_string()),
+        (
+          "X-Video-Duration".to_string(),
+          video.duration_ms.to_string(),
+        ),
+      ],
+      data: image.to_vec(),
+    }))
+  })
+}
+
+pub fn get_info(request: &HttpRequest) -> ServerResult<HttpResponse> {
+  let query: VideoArgs = request.query()?;
+  let videopath: FilePath = request.path()?;
+
+  let identity = FileIdentity::from_path(&videopath)?;
+  let mut response = HttpResponse::default();
+  let etag = identity.etag_with(&format!(
+    "info{:?}",
+    (query.stream, query.decoder, query.width, query.height)
+  ));
+  if response.check_validators(request, &etag, identity.modified) {
+    return Ok(response);
+  }
+
+  let handle = open_video(&videopath, &query)?;
+  let video = handle.lock().unwrap_or_else(|e| e.into_inner());
+
+  response.add_header("Content-Type", "application/json");
+  response.add_content(video.to_json().as_bytes());
+
+  Ok(response)
+}
+
+pub fn get_waveform(request: &HttpRequest) -> ServerResult<HttpResponse> {
+  let query: WaveformArgs = request.query()?;
+  let audiopath: FilePath = request.path()?;
+  let image_options = image_options(request, query.format, query.webp);
+  let variant = format!("{} {}", query.cache_key(), image_options.cache_key());
+
+  let mut response = HttpResponse::default();
+  response.add_header("Vary", "Accept");
+  cached_image(request, response, &audiopath, &variant, || {
+    let audio = open_audio(&audiopath)?;
+    let image = audio.render(query.image)?.encode_as(&image_options)?;
+
+    Ok(Some(CachedImage {
+      headers: vec![
+        (
+          "Content-Type".to_string(),
+          image_options.format.mime_type().to_string(),
+        ),
+        (
+          "X-Audio-Duration".to_string(),
+          audio.duration_ms.to_string(),
+        ),
+        (
+          "X-Audio-Sample-Rate".to_string(),
+          audio.sample_rate.to_string(),
+        ),
+        ("X-Audio-Channels".to_string(), audio.channels.to_string()),
+      ],
+      data: image.to_vec(),
+    }))
+  })
+}
+
+/// Answers with the image `render` produces for `filepath`, unless the request's validators
+/// show the client already has it or the thumbnail cache holds it
+///
+/// `variant` describes everything besides the file that changes the image, `render` returns
+/// the image with its headers, or `None` if there is nothing to show
+fn cached_image(
+  request: &HttpRequest,
+  mut response: HttpResponse,
+  filepath: &str,
+  variant: &str,
+  render: impl FnOnce() -> ServerResult<Option<CachedImage>>,
+) -> ServerResult<HttpResponse> {
+  let identity = FileIdentity::from_path(filepath)?;
+  let etag = identity.etag_with(variant);
+  if response.check_validators(request, &etag, identity.modified) {
+    return Ok(response);
+  }
+
+  let cache = THUMBNAIL_CACHE.get();
+  let cache_key = CacheKey::new(filepath, &identity.key(), variant);
+  let cached = cache.and_then(|cache| cache.get(&cache_key));
+  let hit = cached.is_some();
+  let image = match cached {
+    Some(image) => image,
+    None => match render()? {
+      Some(image) => image,
+      None => return Ok(HttpStatus::NotFound.into()),
+    },
+  };
+
+  for (name, value) in &image.headers {
+    response.add_header(name, value);
+  }
+  if let Some(cache) = cache {
+    response.add_header("X-Cache", if hit { "HIT" } else { "MISS" });
+    if !hit {
+      if let Err(e) = cache.insert(&cache_key, &image) {
+        log!(err@"Could not cache {filepath}\n{e}");
+      }
+    }
+  }
+  response.set_body(HttpBody::Bytes(image.data));
+
+  Ok(response)
+}
+
+/// Opens the video through the pool, stream selection and decoder option errors are the
+/// client's fault while anything else failing is answered with a 500. Files without any
+/// video, like audio without cover art, are answered with a 415
+fn open_video(filepath: &str, query: &VideoArgs) -> ServerResult<VideoHandle> {
+  let options = VideoOptions {
+    stream: query.stream,
+    decoder: query.decoder,
+    width: query.width,
+    height: query.height,
+  };
+  let handle = match VIDEO_POOL.get() {
+    Some(pool) => pool.get(filepath, options),
+    None => Video::open(filepath, options).map(|video| Arc::new(Mutex::new(video))),
+  };
+
+  handle.map_err(|e| match e {
+    VideoError::Rumpeg(
+      RumpegError::StreamMissing(..)
+      | RumpegError::NotAVideoStream(..)
+      | RumpegError::UnknownOption(..),
+    ) => HttpRequestError::Parse(e.to_string()).into(),
+    VideoError::Rumpeg(RumpegError::VideoStreamMissing) => HttpRequestError::UnsupportedMedia(
+      "File has no video stream or cover art, audio is drawn by /waveform".to_string(),
+    )
+    .into(),
+    VideoError::Rumpeg(RumpegError::AVError(_, code, _))
+      if code == ffmpeg::AVERROR(ffmpeg::ENOENT as i32) =>
+    {
+      HttpRequestError::NotFound(filepath.to_string()).into()
+    }
+    e => e.into(),
+  })
+}
+
+/// Opens the audio stream of the file, a file without one is the client's fault while
+/// anything else failing is answered with a 500
+fn open_audio(filepath: &str) -> ServerResult<Audio> {
+  Audio::open(filepath).map_err(|e| match e {
+    AudioError::Rumpeg(RumpegError::AVError(_, code, _))
+      if code == ffmpeg::AVERROR_STREAM_NOT_FOUND =>
+    {
+      HttpRequestError::Parse(e.to_string()).into()
+    }
+    AudioError::Rumpeg(RumpegError::AVError(_, code, _))
+      if code == ffmpeg::AVERROR(ffmpeg::ENOENT as i32) =>
+    {
+      HttpRequestError::NotFound(filepath.to_string()).into()
+    }
+    e => e.into(),
+  })
+}
+
+pub fn cache_stats(_request: &HttpRequest) -> ServerResult<HttpResponse> {
+  let Some(cache) = THUMBNAIL_CACHE.get() else {
+    return Ok(HttpStatus::NotFound.into());
   };
 
+  let stats = cache.stats();
   let mut response = HttpResponse::default();
-  response.add_header("Content-Type", "image/webp");
-  response.add_header("X-Video-Width", &video.width.to_string());
-  response.add_header("X-Video-Height", &video.height.to_string());
-  response.add_header("X-Video-Duration", &video.duration_ms.to_string());
-  response.add_header("X-Video-Extensions", video.extensions);
-  response.add_content(image);
+  response.add_header("Content-Type", "application/json");
+  response.add_header("Cache-Control", "no-store");
+  response.add_content(
+    JsonObject::new()
+      .field("hits", &stats.hits)
+      .field("misses", &stats.misses)
+      .field("entries", &stats.entries)
+      .field("size", &stats.size)
+      .field("max_size", &stats.max_size)
+      .finish()
+      .as_bytes(),
+  );
 
   Ok(response)
 }
@@ -65,11 +323,54 @@ pub struct VideoArgs {
   pub width: i32,
   pub end: SeekPosition,
   pub step: SeekPosition,
+  pub stream: StreamSelector,
+  pub decoder: DecoderOptions,
+  pub seek_mode: SeekMode,
+  /// Left to the `Accept` header when missing
+  pub format: Option<ImageFormat>,
+  pub webp: WebPOptions,
+}
+
+impl VideoArgs {
+  /// Arguments that pick and scale the frames, resolved and in a fixed order so that
+  /// equivalent queries share a key. The encoder settings are keyed with the format the
+  /// request resolves to
+  pub fn cache_key(&self) -> String {
+    let decoder = &self.decoder;
+    // Decoder threads only change how fast the frames are decoded, not the frames
+    format!(
+      "film={} width={} height={} start={} end={} step={} stream={} seek={:?} \
+       skip_loop_filter={:?} skip_idct={:?} lowres={} skip_frame={:?}",
+      self.film,
+      self.width.max(0),
+      self.height.max(0),
+      position_key(self.seek_position),
+      position_key(self.end),
+      position_key(self.step),
+      self.stream,
+      self.seek_mode,
+      decoder.skip_loop_filter,
+      decoder.skip_idct,
+      decoder.lowres,
+      decoder.skip_frame,
+    )
+  }
+}
+
+/// Spells a position the same way whichever unit it was written in, when the unit can be
+/// converted without the stream
+fn position_key(position: SeekPosition) -> String {
+  match position {
+    SeekPosition::Seconds(seconds) => format!("{}ms", seconds.saturating_mul(1000)),
+    SeekPosition::Milliseconds(ms) => format!("{ms}ms"),
+    SeekPosition::Percentage(fraction) => format!("{}%", fraction * 100.),
+    SeekPosition::TimeBase(ts) => format!("{ts}ts"),
+  }
 }
 
 impl FromQueryString for VideoArgs {
   fn from_query_string(query_string: &str) -> HttpRequestResult<Self> {
-    let query = query_string.split('&').collect::<Vec<_>>();
+    let query = parse_query(query_string)?;
     Ok(Self {
       film: find_query_flag(&query, "film"),
       height: find_query_arg(&query, "height"),
@@ -83,25 +384,158 @@ impl FromQueryString for VideoArgs {
         SeekPosition::TimeBase(0) => SeekPosition::TimeBase(1),
         n => n,
       },
+      stream: find_query_arg(&query, "stream"),
+      decoder: DecoderOptions {
+        thread_count: find_query_arg(&query, "threads"),
+        thread_type: find_query_arg(&query, "thread_type"),
+        skip_loop_filter: find_query_arg(&query, "skip_loop_filter"),
+        skip_idct: find_query_arg(&query, "skip_idct"),
+        lowres: find_query_arg(&query, "lowres"),
+        skip_frame: find_query_arg(&query, "skip_frame"),
+      }
+      .or(DECODER_OPTIONS.get().copied().unwrap_or_default()),
+      seek_mode: if find_query_flag_or(&query, "accurate", true) {
+        SeekMode::Accurate
+      } else {
+        SeekMode::Keyframe
+      },
+      format: try_find_query_arg(&query, "format")?,
+      webp: webp_options(&query),
+    })
+  }
+}
+
+#[derive(Debug)]
+pub struct PreviewArgs {
+  pub video: VideoArgs,
+  pub preview: PreviewOptions,
+}
+
+impl PreviewArgs {
+  /// Previews are always animated WebP, so the WebP settings are part of the key
+  pub fn cache_key(&self) -> String {
+    let preview = &self.preview;
+    format!(
+      "{} fps={} loop={} frames={} {}",
+      self.video.cache_key(),
+      preview.fps.max(0.),
+      preview.loop_count,
+      preview.max_frames.min(MAX_PREVIEW_FRAMES),
+      self.video.webp.cache_key(),
+    )
+  }
+}
+
+impl FromQueryString for PreviewArgs {
+  fn from_query_string(query_string: &str) -> HttpRequestResult<Self> {
+    let query = parse_query(query_string)?;
+    let defaults = PreviewOptions::default();
+    Ok(Self {
+      video: VideoArgs::from_query_string(query_string)?,
+      preview: PreviewOptions {
+        fps: find_query_arg(&query, "fps"),
+        loop_count: find_query_arg(&query, "loop"),
+        max_frames: match find_query_arg(&query, "frames") {
+          0 => defaults.max_frames,
+          n => n,
+        },
+      },
+    })
+  }
+}
+
+/// The `format` query arg wins over the `Accept` header, WebP is used when neither names a
+/// supported format
+fn image_options(
+  request: &HttpRequest,
+  format: Option<ImageFormat>,
+  webp: WebPOptions,
+) -> ImageOptions {
+  let format = format.or_else(|| request.header("Accept").and_then(ImageFormat::from_accept));
+  ImageOptions {
+    format: format.unwrap_or_default(),
+    webp,
+  }
+}
+
+/// Encoder options from the query, falling back to the ones from the command line
+fn webp_options(query: &QueryParams) -> WebPOptions {
+  let defaults = WEBP_OPTIONS.get().copied().unwrap_or_default();
+  WebPOptions {
+    quality: find_query_arg_or(query, "q", defaults.quality),
+    lossless: find_query_flag_or(query, "lossless", defaults.lossless),
+    near_lossless: find_query_arg_or(query, "near_lossless", defaults.near_lossless),
+    method: find_query_arg_or(query, "method", defaults.method),
+    preset: find_query_arg_or(query, "preset", defaults.preset),
+    target_size: find_query_arg_or(query, "target_size", defaults.target_size),
+    target_psnr: find_query_arg_or(query, "target_psnr", defaults.target_psnr),
+    alpha_quality: find_query_arg_or(query, "alpha_q", defaults.alpha_quality),
+    sharp_yuv: find_query_flag_or(query, "sharp_yuv", defaults.sharp_yuv),
+    multithreading: find_query_flag_or(query, "mt", defaults.multithreading),
+  }
+}
+
+#[derive(Debug)]
+pub struct WaveformArgs {
+  image: AudioImageOptions,
+  format: Option<ImageFormat>,
+  webp: WebPOptions,
+}
+
+impl WaveformArgs {
+  /// Image options with their defaults filled in, the encoder settings are keyed with the
+  /// format the request resolves to
+  pub fn cache_key(&self) -> String {
+    self.image.cache_key()
+  }
+}
+
+impl FromQueryString for WaveformArgs {
+  fn from_query_string(query_string: &str) -> HttpRequestResult<Self> {
+    let query = parse_query(query_string)?;
+    let defaults = AudioImageOptions::default();
+    Ok(Self {
+      image: AudioImageOptions {
+        mode: find_query_arg(&query, "mode"),
+        width: find_query_arg(&query, "width"),
+        height: find_query_arg(&query, "height"),
+        foreground: find_query_arg_or(&query, "color", defaults.foreground),
+        background: find_query_arg_or(&query, "background", defaults.background),
+      },
+      format: try_find_query_arg(&query, "format")?,
+      webp: webp_options(&query),
     })
   }
 }
 
+/// Captures of the routes serving a file below the media folder
+#[derive(Debug)]
+struct MediaParams {
+  path: String,
+}
+
+from_path!(MediaParams { path });
+
 #[derive(Debug)]
 pub struct FilePath(String);
 
 impl FromPath for FilePath {
-  fn from_path(path: &str) -> HttpRequestResult<Self> {
-    unsafe {
-      path[1..]
-        .split_once('/')
-        .map(|(_, filepath)| {
-          Self(format!(
-            "{}/{filepath}",
-            *MEDIA_FOLDER.load(Ordering::SeqCst)
-          ))
-        })
-        .ok_or(HttpRequestError::Parse("Missing filepath".into()))
+  fn from_path(params: &PathParams) -> HttpRequestResult<Self> {
+    let MediaParams { path: filepath } = MediaParams::from_path(params)?;
+    if filepath.is_empty() {
+      return Err(HttpRequestError::Parse("Missing filepath".into()));
+    }
+
+    let root = unsafe { &*MEDIA_FOLDER.load(Ordering::SeqCst) };
+    let policy = SYMLINK_POLICY.get().copied().unwrap_or_default();
+
+    match resolve_path(root, &filepath, policy) {
+      Ok(path) => Ok(Self(path.to_string_lossy().to_string())),
+      Err(AssetError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+        Err(HttpRequestError::NotFound(filepath))
+      }
+      Err(AssetError::IO(e)) => Err(HttpRequestError::Parse(e.to_string())),
+      Err(_) => Err(HttpRequestError::Forbidden(filepath)),
     }
   }
 }
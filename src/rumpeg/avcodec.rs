@@ -1,8 +1,10 @@
 use super::*;
+use crate::ascii::LogDisplay;
 use crate::ffmpeg;
+use crate::log;
 use std::ops::Deref;
 use std::ops::DerefMut;
-use std::ptr;
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub struct AVCodecContext {
@@ -10,10 +12,150 @@ pub struct AVCodecContext {
   pub format: ffmpeg::AVPixelFormat,
 }
 
+/// Which frames, or parts of decoding, a decoder may skip
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Discard {
+  /// Left to the decoder
+  #[default]
+  Default,
+  None,
+  NonRef,
+  Bidir,
+  NonIntra,
+  NonKey,
+  All,
+}
+
+impl Discard {
+  fn as_option(&self) -> &'static str {
+    match self {
+      Self::Default => "default",
+      Self::None => "none",
+      Self::NonRef => "noref",
+      Self::Bidir => "bidir",
+      Self::NonIntra => "nointra",
+      Self::NonKey => "nokey",
+      Self::All => "all",
+    }
+  }
+}
+
+impl FromStr for Discard {
+  type Err = RumpegError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "default" => Ok(Self::Default),
+      "none" => Ok(Self::None),
+      "noref" | "nonref" => Ok(Self::NonRef),
+      "bidir" => Ok(Self::Bidir),
+      "nointra" | "nonintra" => Ok(Self::NonIntra),
+      "nokey" | "nonkey" => Ok(Self::NonKey),
+      "all" => Ok(Self::All),
+      s => Err(RumpegError::UnknownOption(s.to_string())),
+    }
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadType {
+  /// Left to the decoder
+  #[default]
+  Default,
+  Frame,
+  Slice,
+  Both,
+}
+
+impl ThreadType {
+  fn as_option(&self) -> &'static str {
+    match self {
+      Self::Default | Self::Both => "frame+slice",
+      Self::Frame => "frame",
+      Self::Slice => "slice",
+    }
+  }
+}
+
+impl FromStr for ThreadType {
+  type Err = RumpegError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "default" => Ok(Self::Default),
+      "frame" => Ok(Self::Frame),
+      "slice" => Ok(Self::Slice),
+      "both" | "frame+slice" | "slice+frame" => Ok(Self::Both),
+      s => Err(RumpegError::UnknownOption(s.to_string())),
+    }
+  }
+}
+
+/// Options applied to a decoder when it is opened, anything left at its default
+/// is not passed to FFmpeg at all
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderOptions {
+  /// Number of decoding threads, FFmpeg decodes on a single thread when left at 0
+  pub thread_count: i32,
+  pub thread_type: ThreadType,
+  pub skip_loop_filter: Discard,
+  pub skip_idct: Discard,
+  /// Decodes at `1 / 2^lowres` of the resolution, if the codec supports it
+  pub lowres: i32,
+  /// `NonKey` only decodes keyframes, which makes film strips of long videos much faster
+  pub skip_frame: Discard,
+}
+
+impl DecoderOptions {
+  /// Fills every option left at its default from `fallback`
+  pub fn or(self, fallback: Self) -> Self {
+    fn pick<T: PartialEq + Default>(value: T, fallback: T) -> T {
+      if value == T::default() {
+        fallback
+      } else {
+        value
+      }
+    }
+
+    Self {
+      thread_count: pick(self.thread_count, fallback.thread_count),
+      thread_type: pick(self.thread_type, fallback.thread_type),
+      skip_loop_filter: pick(self.skip_loop_filter, fallback.skip_loop_filter),
+      skip_idct: pick(self.skip_idct, fallback.skip_idct),
+      lowres: pick(self.lowres, fallback.lowres),
+      skip_frame: pick(self.skip_frame, fallback.skip_frame),
+    }
+  }
+
+  pub fn to_dictionary(&self) -> RumpegResult<AVDictionary> {
+    let mut options = AVDictionary::new();
+    if self.thread_count > 0 {
+      options = options.set("threads", &self.thread_count.to_string())?;
+    }
+    if self.thread_type != ThreadType::Default {
+      options = options.set("thread_type", self.thread_type.as_option())?;
+    }
+    if self.skip_loop_filter != Discard::Default {
+      options = options.set("skip_loop_filter", self.skip_loop_filter.as_option())?;
+    }
+    if self.skip_idct != Discard::Default {
+      options = options.set("skip_idct", self.skip_idct.as_option())?;
+    }
+    if self.lowres > 0 {
+      options = options.set("lowres", &self.lowres.to_string())?;
+    }
+    if self.skip_frame != Discard::Default {
+      options = options.set("skip_frame", self.skip_frame.as_option())?;
+    }
+    Ok(options)
+  }
+}
+
 impl AVCodecContext {
-  pub fn new(codecpar: *mut ffmpeg::AVCodecParameters) -> RumpegResult<Self> {
+  pub fn new(stream: &AVStream, options: &DecoderOptions) -> RumpegResult<Self> {
+    let mut dictionary = options.to_dictionary()?;
+    let codecpar = stream.codecpar;
+
     unsafe {
-      let codec = ffmpeg::avcodec_find_decoder((*codecpar).codec_id);
+      let codec = stream.decoder();
       if codec.is_null() {
         return Err(RumpegError::DecoderMissing);
       }
@@ -22,6 +164,11 @@ impl AVCodecContext {
       if ptr.is_null() {
         return Err(RumpegError::AVCodecContextAllocFail);
       }
+      // Owned right away so every early return frees it
+      let mut context = Self {
+        ptr,
+        format: ffmpeg::AVPixelFormat_AV_PIX_FMT_NONE,
+      };
 
       let result = ffmpeg::avcodec_parameters_to_context(ptr, codecpar);
       if result < 0 {
@@ -31,12 +178,19 @@ impl AVCodecContext {
         ));
       }
 
-      let result = ffmpeg::avcodec_open2(ptr, codec, ptr::null_mut());
+      let result = ffmpeg::avcodec_open2(ptr, codec, dictionary.as_mut_ptr());
       if result < 0 {
         return Err(RumpegError::from_code(result, "Could not open AVCodec"));
       }
+      let codec_name = ptr_to_str((*codec).name).unwrap_or("N/A");
+      for key in dictionary.keys() {
+        log!(warn@"Decoder option {key} is not supported by {codec_name}");
+      }
 
-      let format = if (*ptr).pix_fmt == ffmpeg::AVPixelFormat_AV_PIX_FMT_NONE {
+      // Audio has no pixel format to guess
+      let format = if (*ptr).codec_type != ffmpeg::AVMediaType_AVMEDIA_TYPE_VIDEO {
+        (*ptr).pix_fmt
+      } else if (*ptr).pix_fmt == ffmpeg::AVPixelFormat_AV_PIX_FMT_NONE {
         match (*ptr).codec_id {
           ffmpeg::AVCodecID_AV_CODEC_ID_H264
           | ffmpeg::AVCodecID_AV_CODEC_ID_HEVC
@@ -49,19 +203,58 @@ impl AVCodecContext {
       } else {
         (*ptr).pix_fmt
       };
+      // The container only describes the color planes, libvpx adds the alpha plane
+      let format = match format {
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P
+          if stream.has_alpha_mode() && codec_name.starts_with("libvpx") =>
+        {
+          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVA420P
+        }
+        format => format,
+      };
+
+      context.format = match format {
+        // These are deprecated
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ420P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P,
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ422P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV422P,
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ444P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV444P,
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ440P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV440P,
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ411P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV411P,
+        _ => format,
+      };
+      Ok(context)
+    }
+  }
 
-      Ok(Self {
+  /// Allocates an encoder, `configure` sets up the context before it is opened
+  pub fn new_encoder(
+    codec: *const ffmpeg::AVCodec,
+    configure: impl FnOnce(&mut Self),
+    options: &mut AVDictionary,
+  ) -> RumpegResult<Self> {
+    unsafe {
+      let ptr = ffmpeg::avcodec_alloc_context3(codec);
+      if ptr.is_null() {
+        return Err(RumpegError::AVCodecContextAllocFail);
+      }
+
+      let mut context = Self {
         ptr,
-        format: match format {
-          // These are deprecated
-          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ420P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P,
-          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ422P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV422P,
-          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ444P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV444P,
-          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ440P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV440P,
-          ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ411P => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV411P,
-          _ => format,
-        },
-      })
+        format: ffmpeg::AVPixelFormat_AV_PIX_FMT_NONE,
+      };
+      configure(&mut context);
+      context.format = context.pix_fmt;
+
+      let result = ffmpeg::avcodec_open2(ptr, codec, options.as_mut_ptr());
+      if result < 0 {
+        return Err(RumpegError::from_code(result, "Could not open encoder"));
+      }
+      let codec_name = ptr_to_str((*codec).name).unwrap_or("N/A");
+      for key in options.keys() {
+        log!(warn@"Encoder option {key} is not supported by {codec_name}");
+      }
+
+      Ok(context)
     }
   }
 
@@ -0,0 +1,73 @@
+use std::ffi::CString;
+use std::ptr;
+
+use super::*;
+use crate::ffmpeg;
+
+/// Owned set of key/value options, passed to FFmpeg functions taking an `AVDictionary **`
+#[derive(Debug)]
+pub struct AVDictionary {
+  ptr: *mut ffmpeg::AVDictionary,
+}
+
+impl AVDictionary {
+  pub fn new() -> Self {
+    Self {
+      ptr: ptr::null_mut(),
+    }
+  }
+
+  pub fn set(mut self, key: &str, value: &str) -> RumpegResult<Self> {
+    let key = CString::new(key)?;
+    let value = CString::new(value)?;
+
+    unsafe {
+      match ffmpeg::av_dict_set(&mut self.ptr, key.as_ptr(), value.as_ptr(), 0) {
+        e if e < 0 => Err(RumpegError::from_code(
+          e,
+          &format!("Could not set option {key:?}"),
+        )),
+        _ => Ok(self),
+      }
+    }
+  }
+
+  /// Keys left in the dictionary, after a call that consumes the options it recognizes
+  pub fn keys<'a>(&self) -> Vec<&'a str> {
+    let mut keys = Vec::new();
+    unsafe {
+      let mut entry = ptr::null();
+      loop {
+        entry = ffmpeg::av_dict_get(
+          self.ptr,
+          b"\0".as_ptr() as *const _,
+          entry,
+          ffmpeg::AV_DICT_IGNORE_SUFFIX as i32,
+        );
+        let Some(current) = entry.as_ref() else {
+          break;
+        };
+        keys.extend(ptr_to_str(current.key));
+      }
+    }
+    keys
+  }
+
+  pub fn as_mut_ptr(&mut self) -> *mut *mut ffmpeg::AVDictionary {
+    &mut self.ptr
+  }
+}
+
+impl Default for AVDictionary {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Drop for AVDictionary {
+  fn drop(&mut self) {
+    unsafe {
+      ffmpeg::av_dict_free(&mut self.ptr);
+    }
+  }
+}
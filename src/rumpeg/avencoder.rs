@@ -0,0 +1,292 @@
+use std::ffi::CString;
+use std::ops::DerefMut;
+use std::{ptr, slice};
+
+use super::*;
+use crate::ffmpeg;
+use crate::image::{EncodedImage, ImageEncoder, ImageFormat};
+
+/// AV1 encoders tried for AVIF, in order of preference
+const AV1_ENCODERS: [&str; 3] = ["libaom-av1", "libsvtav1", "librav1e"];
+
+/// Encodes single frames with FFmpeg, for the formats libwebp does not cover
+#[derive(Debug, Clone, Copy)]
+pub struct AVImageEncoder {
+  format: ImageFormat,
+  /// Between 0 and 100, ignored by lossless formats
+  quality: f32,
+}
+
+impl AVImageEncoder {
+  pub fn new(format: ImageFormat, quality: f32) -> Self {
+    Self { format, quality }
+  }
+
+  /// Whether FFmpeg was built with an encoder for `format`
+  pub fn supports(format: ImageFormat) -> bool {
+    Self::new(format, 0.).find_codec().is_ok()
+  }
+
+  fn find_codec(&self) -> RumpegResult<*const ffmpeg::AVCodec> {
+    unsafe {
+      let codec = match self.format {
+        ImageFormat::Png => ffmpeg::avcodec_find_encoder(ffmpeg::AVCodecID_AV_CODEC_ID_PNG),
+        ImageFormat::Jpeg => ffmpeg::avcodec_find_encoder(ffmpeg::AVCodecID_AV_CODEC_ID_MJPEG),
+        ImageFormat::Avif => AV1_ENCODERS
+          .iter()
+          .filter_map(|name| CString::new(*name).ok())
+          .map(|name| ffmpeg::avcodec_find_encoder_by_name(name.as_ptr()))
+          .find(|codec| !codec.is_null())
+          .unwrap_or(ptr::null()),
+        ImageFormat::WebP => ptr::null(),
+      };
+
+      if codec.is_null() {
+        return Err(RumpegError::EncoderMissing(
+          self.format.extension().to_string(),
+        ));
+      }
+      Ok(codec)
+    }
+  }
+
+  fn pixel_format(
+    &self,
+    codec: *const ffmpeg::AVCodec,
+    source: ffmpeg::AVPixelFormat,
+  ) -> ffmpeg::AVPixelFormat {
+    unsafe {
+      match self.format {
+        // JPEG decoders assume full range, and there is no alpha to keep
+        ImageFormat::Jpeg => ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVJ420P,
+        _ if (*codec).pix_fmts.is_null() => source,
+        _ => {
+          let has_alpha = source.has_alpha() as i32;
+          ffmpeg::avcodec_find_best_pix_fmt_of_list(
+            (*codec).pix_fmts,
+            source,
+            has_alpha,
+            ptr::null_mut(),
+          )
+        }
+      }
+    }
+  }
+
+  /// Private options of the AV1 encoders, which all name their quality and speed differently
+  fn options(&self, codec_name: &str) -> RumpegResult<AVDictionary> {
+    let level = |max: f32| ((100. - self.quality.clamp(0., 100.)) * max / 100.).round() as i32;
+    let options = AVDictionary::new();
+    match codec_name {
+      "libaom-av1" => options
+        .set("crf", &level(63.).to_string())?
+        .set("cpu-used", "6"),
+      "libsvtav1" => options
+        .set("crf", &level(63.).to_string())?
+        .set("preset", "8"),
+      "librav1e" => options
+        .set("qp", &level(255.).to_string())?
+        .set("speed", "6"),
+      _ => Ok(options),
+    }
+  }
+}
+
+impl ImageEncoder for AVImageEncoder {
+  fn encode(&self, frame: &AVFrame) -> RumpegResult<EncodedImage> {
+    let codec = self.find_codec()?;
+    let codec_name = unsafe { ptr_to_str((*codec).name).unwrap_or("N/A") };
+    // Raw AV1 packets are not images by themselves, they need the AVIF container
+    let mut output = match self.format {
+      ImageFormat::Avif => Some(AVOutputContext::new("avif")?),
+      _ => None,
+    };
+
+    let format = self.pixel_format(codec, frame.format);
+    let mut frame = if format == frame.format {
+      frame.try_clone()?
+    } else {
+      SwsContext::with_format(SwsFrameProperties::from(frame), format)?.transform(frame, None)?
+    };
+    frame.pts = 0;
+
+    let global_header = output
+      .as_ref()
+      .is_some_and(|output| output.needs_global_header());
+    let mut options = self.options(codec_name)?;
+    let codec_context = AVCodecContext::new_encoder(
+      codec,
+      |context| {
+        context.width = frame.width;
+        context.height = frame.height;
+        context.pix_fmt = format;
+        context.time_base = ffmpeg::AVRational { num: 1, den: 1 };
+        // Quality is set through private options instead of a target bit rate
+        context.bit_rate = 0;
+        if self.format == ImageFormat::Jpeg {
+          let qscale = 2. + (100. - self.quality.clamp(0., 100.)) * 29. / 100.;
+          context.flags |= ffmpeg::AV_CODEC_FLAG_QSCALE as i32;
+          context.global_quality = (qscale * ffmpeg::FF_QP2LAMBDA as f32) as i32;
+        }
+        if global_header {
+          context.flags |= ffmpeg::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+        }
+      },
+      &mut options,
+    )?;
+
+    frame.send(codec_context.as_ptr())?;
+    // Flushes the encoder, some only output a packet once they know no more frames follow
+    unsafe {
+      ffmpeg::avcodec_send_frame(codec_context.as_ptr(), ptr::null());
+    }
+    let mut packet = AVPacket::empty();
+    if !packet.receive(codec_context.as_ptr())? {
+      return Err(RumpegError::from_code(
+        ffmpeg::AVERROR_EOF,
+        "Encoder returned no image",
+      ));
+    }
+
+    Ok(EncodedImage::Bytes(match &mut output {
+      Some(output) => {
+        output.add_stream(&codec_context)?;
+        output.write_packet(&codec_context, &mut packet)?;
+        output.finish()?
+      }
+      None => packet.data().to_vec(),
+    }))
+  }
+}
+
+/// Muxes a single stream into a buffer in memory instead of a file
+pub(crate) struct AVOutputContext {
+  ptr: *mut ffmpeg::AVFormatContext,
+  stream: *mut ffmpeg::AVStream,
+}
+
+impl AVOutputContext {
+  pub(crate) fn new(format_name: &str) -> RumpegResult<Self> {
+    let format_name = CString::new(format_name)?;
+
+    unsafe {
+      let mut ptr = ptr::null_mut();
+      let result = ffmpeg::avformat_alloc_output_context2(
+        &mut ptr,
+        ptr::null(),
+        format_name.as_ptr(),
+        ptr::null(),
+      );
+      if result < 0 {
+        return Err(RumpegError::from_code(
+          result,
+          &format!("Could not create {format_name:?} output"),
+        ));
+      }
+
+      let output = Self {
+        ptr,
+        stream: ptr::null_mut(),
+      };
+      let result = ffmpeg::avio_open_dyn_buf(&mut (*ptr).pb);
+      if result < 0 {
+        return Err(RumpegError::from_code(
+          result,
+          "Could not allocate output buffer",
+        ));
+      }
+      Ok(output)
+    }
+  }
+
+  pub(crate) fn needs_global_header(&self) -> bool {
+    unsafe { (*(*self.ptr).oformat).flags & ffmpeg::AVFMT_GLOBALHEADER as i32 != 0 }
+  }
+
+  /// Adds the stream written by `codec_context` and writes the header
+  pub(crate) fn add_stream(&mut self, codec_context: &AVCodecContext) -> RumpegResult {
+    unsafe {
+      let stream = ffmpeg::avformat_new_stream(self.ptr, ptr::null());
+      if stream.is_null() {
+        return Err(RumpegError::from_code(
+          ffmpeg::AVERROR(ffmpeg::ENOMEM as i32),
+          "Could not add output stream",
+        ));
+      }
+
+      let result =
+        ffmpeg::avcodec_parameters_from_context((*stream).codecpar, codec_context.as_ptr());
+      if result < 0 {
+        return Err(RumpegError::from_code(
+          result,
+          "Could not copy encoder parameters",
+        ));
+      }
+      (*stream).time_base = codec_context.time_base;
+
+      let result = ffmpeg::avformat_write_header(self.ptr, ptr::null_mut());
+      if result < 0 {
+        return Err(RumpegError::from_code(result, "Could not write header"));
+      }
+      self.stream = stream;
+      Ok(())
+    }
+  }
+
+  /// Writes a packet received from `codec_context`, after [`Self::add_stream`]
+  pub(crate) fn write_packet(
+    &mut self,
+    codec_context: &AVCodecContext,
+    packet: &mut AVPacket,
+  ) -> RumpegResult {
+    unsafe {
+      packet.stream_index = (*self.stream).index;
+      ffmpeg::av_packet_rescale_ts(
+        packet.deref_mut(),
+        codec_context.time_base,
+        (*self.stream).time_base,
+      );
+      let result = ffmpeg::av_write_frame(self.ptr, packet.deref_mut());
+      if result < 0 {
+        return Err(RumpegError::from_code(result, "Could not write packet"));
+      }
+      Ok(())
+    }
+  }
+
+  /// Writes the trailer, returning the whole file
+  pub(crate) fn finish(&mut self) -> RumpegResult<Vec<u8>> {
+    let result = unsafe { ffmpeg::av_write_trailer(self.ptr) };
+    if result < 0 {
+      return Err(RumpegError::from_code(result, "Could not write trailer"));
+    }
+    Ok(self.take_buffer())
+  }
+
+  fn take_buffer(&mut self) -> Vec<u8> {
+    unsafe {
+      if (*self.ptr).pb.is_null() {
+        return Vec::new();
+      }
+
+      let mut buffer = ptr::null_mut();
+      let size = ffmpeg::avio_close_dyn_buf((*self.ptr).pb, &mut buffer);
+      (*self.ptr).pb = ptr::null_mut();
+      let data = match buffer.is_null() {
+        true => Vec::new(),
+        false => slice::from_raw_parts(buffer, size as usize).to_vec(),
+      };
+      ffmpeg::av_free(buffer as *mut _);
+      data
+    }
+  }
+}
+
+impl Drop for AVOutputContext {
+  fn drop(&mut self) {
+    self.take_buffer();
+    unsafe {
+      ffmpeg::avformat_free_context(self.ptr);
+    }
+  }
+}
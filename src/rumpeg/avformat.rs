@@ -14,7 +14,19 @@ pub struct AVFormatContext {
 }
 
 impl AVFormatContext {
-  pub fn new(filepath: &str) -> RumpegResult<Self> {
+  pub fn new(filepath: &str, stream: StreamSelector) -> RumpegResult<Self> {
+    Self::open(filepath, |ptr| AVStream::new(ptr, stream))
+  }
+
+  /// Opens `filepath` on its main audio stream instead of a video stream
+  pub fn new_audio(filepath: &str) -> RumpegResult<Self> {
+    Self::open(filepath, AVStream::new_audio)
+  }
+
+  fn open(
+    filepath: &str,
+    find_stream: impl FnOnce(*mut ffmpeg::AVFormatContext) -> RumpegResult<AVStream>,
+  ) -> RumpegResult<Self> {
     let filename = CString::new(filepath)?;
 
     unsafe {
@@ -42,7 +54,7 @@ impl AVFormatContext {
         return Err(RumpegError::VideoFormatMissing);
       }
 
-      let stream = AVStream::new(ptr).map_err(|e| {
+      let stream = find_stream(ptr).map_err(|e| {
         ffmpeg::avformat_close_input(&mut ptr);
         e
       })?;
@@ -72,12 +84,31 @@ impl AVFormatContext {
     }
   }
 
+  /// Lists every stream in the container, not just the one being decoded
+  pub fn streams<'a>(&self) -> Vec<StreamInfo<'a>> {
+    unsafe {
+      (0..(*self.ptr).nb_streams)
+        .map(|i| StreamInfo::new(*(*self.ptr).streams.add(i as usize)))
+        .collect()
+    }
+  }
+
+  /// Iterates over the decoded audio of the stream, resampled to mono
+  pub fn samples<'a>(
+    &self,
+    codec_context: *mut ffmpeg::AVCodecContext,
+    swr_context: &'a SwrContext,
+  ) -> AVSampleIter<'a> {
+    AVSampleIter::new(self.ptr, codec_context, self.stream.index, swr_context)
+  }
+
   pub fn frames(
     &self,
     codec_context: *mut ffmpeg::AVCodecContext,
     start: SeekPosition,
     end: SeekPosition,
     step: SeekPosition,
+    mode: SeekMode,
   ) -> AVFrameIter {
     let step = std::cmp::max(1, self.stream.as_time_base(step));
     AVFrameIter::new(
@@ -88,6 +119,7 @@ impl AVFormatContext {
       self.stream.as_time_base(end),
       step,
       step > self.stream.as_time_base(SeekPosition::Seconds(2)),
+      mode,
     )
   }
 }
@@ -2,9 +2,9 @@ use super::avpixel::AVPixelFormatMethods;
 use super::*;
 use crate::ascii::LogDisplay;
 use crate::ffmpeg;
+use crate::image::{EncodedImage, ImageOptions};
 use crate::log;
 use crate::math;
-use crate::webp::WebPEncoder;
 use std::fmt::Display;
 use std::ops::{Deref, DerefMut};
 use std::slice;
@@ -35,6 +35,15 @@ impl AVFrame {
     }
   }
 
+  /// New reference to the same picture, without copying its data
+  pub fn try_clone(&self) -> RumpegResult<Self> {
+    let ptr = unsafe { ffmpeg::av_frame_clone(self.ptr) };
+    if ptr.is_null() {
+      return Err(RumpegError::AVFrameCreation);
+    }
+    Ok(Self { ptr })
+  }
+
   pub fn new(format: i32, width: i32, height: i32) -> RumpegResult<Self> {
     let mut frame = Self::empty()?;
     frame.format = format;
@@ -85,7 +94,7 @@ impl AVFrame {
 
     let [a, b, u, c, d, v, _, _, w] = *transform;
 
-    for plane in 0..3 {
+    for plane in 0..self.plane_count() {
       let src_stride = self.linesize[plane] as usize;
       let src_height = self.plane_height(plane) as f32;
       let dst_stride = dest.linesize[plane];
@@ -136,8 +145,22 @@ impl AVFrame {
     }
   }
 
-  pub fn encode_as_webp<'a>(&self) -> RumpegResult<&'a [u8]> {
-    Ok(WebPEncoder::new(self, 50.)?.encode()?)
+  pub fn send(&self, codec_context: *mut ffmpeg::AVCodecContext) -> RumpegResult {
+    unsafe {
+      match ffmpeg::avcodec_send_frame(codec_context, self.ptr) {
+        e if e < 0 => Err(RumpegError::from_code(e, "Error sending frame")),
+        _ => Ok(()),
+      }
+    }
+  }
+
+  pub fn encode_as(&self, options: &ImageOptions) -> RumpegResult<EncodedImage> {
+    options.encoder().encode(self)
+  }
+
+  /// Number of planes the pixel format is stored in, packed formats like RGB use a single one
+  pub fn plane_count(&self) -> usize {
+    unsafe { ffmpeg::av_pix_fmt_count_planes(self.format).max(0) as usize }
   }
 
   pub fn plane_height(&self, plane: usize) -> i32 {
@@ -188,6 +211,16 @@ impl Drop for AVFrame {
   }
 }
 
+/// How closely the frames returned by [`AVFrameIter`] match the requested timestamps
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SeekMode {
+  /// Decodes forward from the previous keyframe up to each timestamp
+  #[default]
+  Accurate,
+  /// Returns the keyframe at or before each timestamp, without decoding anything after it
+  Keyframe,
+}
+
 #[derive(Debug)]
 pub struct AVFrameIter {
   format_context: *mut ffmpeg::AVFormatContext,
@@ -197,9 +230,17 @@ pub struct AVFrameIter {
   next_timestamp: i64,
   end: i64,
   seek_to_step: bool,
+  mode: SeekMode,
+  /// Timestamp of the last frame returned, keyframe mode never returns a frame twice
+  last_pts: i64,
+  /// Set once the whole stream was read and only buffered frames are left
+  draining: bool,
+  /// Restored on drop, since keyframe mode overrides it on a shared codec context
+  skip_frame: ffmpeg::AVDiscard,
 }
 
 impl AVFrameIter {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     format_context: *mut ffmpeg::AVFormatContext,
     codec_context: *mut ffmpeg::AVCodecContext,
@@ -208,7 +249,15 @@ impl AVFrameIter {
     end: i64,
     step: i64,
     seek_to_step: bool,
+    mode: SeekMode,
   ) -> Self {
+    let skip_frame = unsafe { (*codec_context).skip_frame };
+    if mode == SeekMode::Keyframe {
+      unsafe {
+        (*codec_context).skip_frame = ffmpeg::AVDiscard_AVDISCARD_NONKEY;
+      }
+    }
+
     Self {
       format_context,
       codec_context,
@@ -216,7 +265,12 @@ impl AVFrameIter {
       step,
       next_timestamp: start,
       end,
-      seek_to_step,
+      // Every target is reached by seeking when frames in between are never decoded
+      seek_to_step: seek_to_step || mode == SeekMode::Keyframe,
+      mode,
+      last_pts: ffmpeg::AV_NOPTS_VALUE,
+      draining: false,
+      skip_frame,
     }
   }
 }
@@ -229,50 +283,61 @@ impl Iterator for AVFrameIter {
       return None;
     }
 
-    let Ok(mut frame) = AVFrame::empty() else {return None};
+    let Ok(mut frame) = AVFrame::empty() else {
+      return None;
+    };
     let mut packet = AVPacket::empty();
 
     loop {
-      match packet.read(self.format_context) {
-        Ok(..) => unsafe {
-          if packet.stream_index == self.stream_index {
-            if let Err(e) = packet.send(self.codec_context) {
-              log!(warn@"{e}");
-            }
-            match frame.receive_packet(self.codec_context) {
-              Ok(changed) => {
-                if changed && frame.pts >= self.next_timestamp {
-                  self.next_timestamp = frame.pts + self.step;
-                  if self.seek_to_step {
-                    ffmpeg::avcodec_flush_buffers(self.codec_context);
-                    ffmpeg::avformat_seek_file(
-                      self.format_context,
-                      self.stream_index,
-                      0,
-                      self.next_timestamp,
-                      self.next_timestamp,
-                      ffmpeg::AVSEEK_FLAG_BACKWARD as i32,
-                    );
-                  }
-                  return Some(frame);
-                }
-              }
-              Err(e) => {
-                log!(warn@"{e}");
-                return None;
-              }
-            };
+      // A packet can hold several frames, so the decoder is emptied before sending another
+      match frame.receive_packet(self.codec_context) {
+        Ok(true) => unsafe {
+          let reached = match self.mode {
+            SeekMode::Accurate => frame.pts >= self.next_timestamp,
+            // Targets between the same two keyframes all seek to the earlier one, once it
+            // was returned reading goes on to the next keyframe instead
+            SeekMode::Keyframe => frame.pts == ffmpeg::AV_NOPTS_VALUE || frame.pts > self.last_pts,
+          };
+          if !reached {
+            continue;
+          }
+          // Only keyframes read forward past a duplicate can lie beyond the end
+          if self.mode == SeekMode::Keyframe && frame.pts >= self.end {
+            return None;
           }
+          self.last_pts = frame.pts;
+
+          // Keyframes can lie before the target, stepping from them could return
+          // the same keyframe forever
+          self.next_timestamp = match self.mode {
+            SeekMode::Accurate => frame.pts,
+            SeekMode::Keyframe => std::cmp::max(self.next_timestamp, frame.pts),
+          } + self.step;
+          if self.seek_to_step && !self.draining {
+            ffmpeg::avcodec_flush_buffers(self.codec_context);
+            ffmpeg::avformat_seek_file(
+              self.format_context,
+              self.stream_index,
+              0,
+              self.next_timestamp,
+              self.next_timestamp,
+              ffmpeg::AVSEEK_FLAG_BACKWARD as i32,
+            );
+          }
+          return Some(frame);
         },
+        Ok(false) if self.draining => return None,
+        Ok(false) => {}
+        Err(RumpegError::AVError(_, ffmpeg::AVERROR_EOF, _)) => return None,
         Err(e) => {
-          if let RumpegError::AVError(_, code, _) = e {
-            if code == ffmpeg::AVERROR_EOF {
-              return None;
-            }
-          }
-          log!(warn@"Encountered AVError while reading frame {e}");
+          log!(warn@"{e}");
+          return None;
         }
       }
+
+      if !packet.feed(self.format_context, self.codec_context, self.stream_index) {
+        self.draining = true;
+      }
     }
   }
 }
@@ -280,8 +345,155 @@ impl Iterator for AVFrameIter {
 impl Drop for AVFrameIter {
   fn drop(&mut self) {
     unsafe {
+      (*self.codec_context).skip_frame = self.skip_frame;
       ffmpeg::avcodec_flush_buffers(self.codec_context);
       ffmpeg::avformat_flush(self.format_context);
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+
+  const FRAME_COUNT: i64 = 30;
+
+  /// Encodes a short MPEG-4 clip with B-frames, so the decoder holds frames back until EOF
+  fn encode_clip(name: &str) -> PathBuf {
+    fn write_packets(
+      output: &mut AVOutputContext,
+      codec_context: &AVCodecContext,
+      packet: &mut AVPacket,
+      reordered: &mut usize,
+    ) {
+      loop {
+        match packet.receive(codec_context.as_ptr()) {
+          Ok(true) => {
+            // Only B-frames make the encoder emit packets out of presentation order
+            if packet.dts != packet.pts {
+              *reordered += 1;
+            }
+            output.write_packet(codec_context, packet).unwrap()
+          }
+          Ok(false) | Err(RumpegError::AVError(_, ffmpeg::AVERROR_EOF, _)) => break,
+          Err(e) => panic!("{e}"),
+        }
+      }
+    }
+
+    let codec = unsafe { ffmpeg::avcodec_find_encoder(ffmpeg::AVCodecID_AV_CODEC_ID_MPEG4) };
+    assert!(!codec.is_null(), "No MPEG-4 encoder");
+    let mut output = AVOutputContext::new("nut").unwrap();
+    let global_header = output.needs_global_header();
+    let codec_context = AVCodecContext::new_encoder(
+      codec,
+      |context| {
+        context.width = 64;
+        context.height = 64;
+        context.pix_fmt = ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P;
+        context.time_base = ffmpeg::AVRational { num: 1, den: 25 };
+        context.framerate = ffmpeg::AVRational { num: 25, den: 1 };
+        context.gop_size = 12;
+        context.max_b_frames = 2;
+        if global_header {
+          context.flags |= ffmpeg::AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+        }
+      },
+      &mut AVDictionary::new(),
+    )
+    .unwrap();
+    output.add_stream(&codec_context).unwrap();
+
+    let mut packet = AVPacket::empty();
+    let mut reordered = 0;
+    for i in 0..FRAME_COUNT {
+      let mut frame = AVFrame::new(ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P, 64, 64).unwrap();
+      frame.data_mut(0).fill((i * 8) as u8);
+      frame.data_mut(1).fill(128);
+      frame.data_mut(2).fill(128);
+      frame.pts = i;
+      frame.send(codec_context.as_ptr()).unwrap();
+      write_packets(&mut output, &codec_context, &mut packet, &mut reordered);
+    }
+    unsafe {
+      ffmpeg::avcodec_send_frame(codec_context.as_ptr(), std::ptr::null());
+    }
+    write_packets(&mut output, &codec_context, &mut packet, &mut reordered);
+    assert!(reordered > 0, "Encoder emitted no B-frames");
+
+    let path = std::env::temp_dir().join(format!("rumpeg-{name}-{}.nut", std::process::id()));
+    std::fs::write(&path, output.finish().unwrap()).unwrap();
+    path
+  }
+
+  fn decoded_timestamps(
+    format_context: &AVFormatContext,
+    codec_context: &AVCodecContext,
+    start: i64,
+    mode: SeekMode,
+  ) -> Vec<i64> {
+    format_context
+      .frames(
+        codec_context.as_ptr(),
+        SeekPosition::TimeBase(start),
+        SeekPosition::TimeBase(i64::MAX),
+        SeekPosition::TimeBase(1),
+        mode,
+      )
+      .map(|frame| frame.pts)
+      .collect()
+  }
+
+  #[test]
+  fn returns_frames_buffered_at_eof() {
+    let path = encode_clip("drain");
+    let format_context =
+      AVFormatContext::new(path.to_str().unwrap(), StreamSelector::Best).unwrap();
+    let codec_context =
+      AVCodecContext::new(&format_context.stream, &DecoderOptions::default()).unwrap();
+    assert!(codec_context.has_b_frames > 0, "Clip has no B-frames");
+
+    let timestamps = decoded_timestamps(&format_context, &codec_context, 0, SeekMode::Accurate);
+    std::fs::remove_file(&path).ok();
+
+    // The decoder holds frames back until EOF, so the last ones only come out of the drain
+    assert_eq!(timestamps.len(), FRAME_COUNT as usize);
+    assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+  }
+
+  #[test]
+  fn returns_last_frame_when_seeking_to_it() {
+    let path = encode_clip("last");
+    let format_context =
+      AVFormatContext::new(path.to_str().unwrap(), StreamSelector::Best).unwrap();
+    let codec_context =
+      AVCodecContext::new(&format_context.stream, &DecoderOptions::default()).unwrap();
+
+    let last = *decoded_timestamps(&format_context, &codec_context, 0, SeekMode::Accurate)
+      .last()
+      .unwrap();
+    format_context.seek(SeekPosition::TimeBase(last)).unwrap();
+    let timestamps = decoded_timestamps(&format_context, &codec_context, last, SeekMode::Accurate);
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(timestamps, [last]);
+  }
+
+  #[test]
+  fn returns_each_keyframe_once() {
+    let path = encode_clip("keyframes");
+    let format_context =
+      AVFormatContext::new(path.to_str().unwrap(), StreamSelector::Best).unwrap();
+    let codec_context =
+      AVCodecContext::new(&format_context.stream, &DecoderOptions::default()).unwrap();
+
+    // Every frame is a target, so all but the keyframes seek to one already returned
+    let timestamps = decoded_timestamps(&format_context, &codec_context, 0, SeekMode::Keyframe);
+    std::fs::remove_file(&path).ok();
+
+    assert!(timestamps.len() > 1);
+    assert!(timestamps.len() < FRAME_COUNT as usize);
+    assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+  }
+}
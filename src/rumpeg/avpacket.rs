@@ -1,7 +1,10 @@
 use std::ops::{Deref, DerefMut};
+use std::slice;
 
 use super::*;
+use crate::ascii::LogDisplay;
 use crate::ffmpeg;
+use crate::log;
 
 pub struct AVPacket {
   ptr: *mut ffmpeg::AVPacket,
@@ -28,6 +31,54 @@ impl AVPacket {
     }
   }
 
+  /// Reads the next packet of `stream_index` and sends it to the decoder, returns `false`
+  /// once the input is exhausted and the decoder was put in draining mode instead,
+  /// so it returns the frames it still buffers
+  pub fn feed(
+    &mut self,
+    format_context: *mut ffmpeg::AVFormatContext,
+    codec_context: *mut ffmpeg::AVCodecContext,
+    stream_index: i32,
+  ) -> bool {
+    loop {
+      match self.read(format_context) {
+        Ok(..) if self.stream_index != stream_index => continue,
+        Ok(..) => {
+          if let Err(e) = self.send(codec_context) {
+            log!(warn@"{e}");
+          }
+          return true;
+        }
+        Err(RumpegError::AVError(_, ffmpeg::AVERROR_EOF, _)) => unsafe {
+          ffmpeg::avcodec_send_packet(codec_context, std::ptr::null());
+          return false;
+        },
+        Err(e) => log!(warn@"Encountered AVError while reading packet {e}"),
+      }
+    }
+  }
+
+  /// Receives an encoded packet, `false` when the encoder needs more frames first
+  pub fn receive(&mut self, codec_context: *mut ffmpeg::AVCodecContext) -> RumpegResult<bool> {
+    unsafe {
+      match ffmpeg::avcodec_receive_packet(codec_context, self.ptr) {
+        0 => Ok(true),
+        e if e == ffmpeg::AVERROR(ffmpeg::EAGAIN as i32) => Ok(false),
+        e => Err(RumpegError::from_code(
+          e,
+          "Encountered AVError while receiving packet",
+        )),
+      }
+    }
+  }
+
+  pub fn data(&self) -> &[u8] {
+    if self.data.is_null() {
+      return &[];
+    }
+    unsafe { slice::from_raw_parts(self.data, self.size as usize) }
+  }
+
   pub fn send(&mut self, codec_context: *mut ffmpeg::AVCodecContext) -> RumpegResult {
     unsafe {
       match ffmpeg::avcodec_send_packet(codec_context, self.ptr) {
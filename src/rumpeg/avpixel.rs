@@ -10,6 +10,14 @@ pub trait AVPixelFormatMethods: Into<i32> + Copy {
     }
   }
 
+  /// Whether the descriptor flags an alpha channel, which includes `PAL8` as its palette
+  /// entries carry alpha
+  fn has_alpha(&self) -> bool {
+    self
+      .av_pix_fmt_descriptor()
+      .is_some_and(|desc| desc.flags & ffmpeg::AV_PIX_FMT_FLAG_ALPHA as u64 != 0)
+  }
+
   fn av_pix_fmt_name<'a>(&self) -> &'a str {
     unsafe {
       ffmpeg::av_get_pix_fmt_name((*self).into())
@@ -0,0 +1,91 @@
+use super::*;
+use crate::ascii::LogDisplay;
+use crate::ffmpeg;
+use crate::log;
+
+/// Decodes an audio stream from start to end, yielding the mono samples of each frame
+#[derive(Debug)]
+pub struct AVSampleIter<'a> {
+  format_context: *mut ffmpeg::AVFormatContext,
+  codec_context: *mut ffmpeg::AVCodecContext,
+  stream_index: i32,
+  swr_context: &'a SwrContext,
+  draining: bool,
+  done: bool,
+}
+
+impl<'a> AVSampleIter<'a> {
+  pub fn new(
+    format_context: *mut ffmpeg::AVFormatContext,
+    codec_context: *mut ffmpeg::AVCodecContext,
+    stream_index: i32,
+    swr_context: &'a SwrContext,
+  ) -> Self {
+    Self {
+      format_context,
+      codec_context,
+      stream_index,
+      swr_context,
+      draining: false,
+      done: false,
+    }
+  }
+
+  /// Receives the next decoded frame, reading packets until the decoder has one
+  fn next_frame(&mut self) -> Option<AVFrame> {
+    let mut frame = AVFrame::empty().ok()?;
+    let mut packet = AVPacket::empty();
+
+    loop {
+      match frame.receive_packet(self.codec_context) {
+        Ok(true) => return Some(frame),
+        Ok(false) if !self.draining => {}
+        Ok(false) => return None,
+        Err(e) => {
+          // The decoder reports EOF once drained
+          if !matches!(e, RumpegError::AVError(_, ffmpeg::AVERROR_EOF, _)) {
+            log!(warn@"{e}");
+          }
+          return None;
+        }
+      }
+
+      if !packet.feed(self.format_context, self.codec_context, self.stream_index) {
+        self.draining = true;
+      }
+    }
+  }
+}
+
+impl<'a> Iterator for AVSampleIter<'a> {
+  type Item = Vec<f32>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+
+    let frame = self.next_frame();
+    if frame.is_none() {
+      self.done = true;
+    }
+
+    match self.swr_context.convert(frame.as_ref()) {
+      Ok(samples) => Some(samples),
+      Err(e) => {
+        log!(warn@"{e}");
+        self.done = true;
+        None
+      }
+    }
+  }
+}
+
+impl<'a> Drop for AVSampleIter<'a> {
+  fn drop(&mut self) {
+    unsafe {
+      ffmpeg::avcodec_flush_buffers(self.codec_context);
+      ffmpeg::avformat_flush(self.format_context);
+    }
+  }
+}
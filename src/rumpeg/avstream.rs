@@ -1,7 +1,11 @@
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::str::FromStr;
 
 use super::*;
 use crate::ascii::LogDisplay;
+use crate::json::{JsonObject, ToJson};
 use crate::{ffmpeg, log, math::Matrix3x3};
 
 #[derive(Debug)]
@@ -11,7 +15,75 @@ pub struct AVStream {
 }
 
 impl AVStream {
-  pub(super) fn new(format_context: *mut ffmpeg::AVFormatContext) -> RumpegResult<Self> {
+  pub(super) fn new(
+    format_context: *mut ffmpeg::AVFormatContext,
+    selector: StreamSelector,
+  ) -> RumpegResult<Self> {
+    unsafe {
+      let result = ffmpeg::avformat_find_stream_info(format_context, std::ptr::null_mut());
+      if result < 0 {
+        return Err(RumpegError::from_code(result, "Could not find stream info"));
+      }
+
+      let streams = std::slice::from_raw_parts(
+        (*format_context).streams,
+        (*format_context).nb_streams as usize,
+      );
+      let is_video = |stream: &&*mut ffmpeg::AVStream| {
+        (*(**stream).codecpar).codec_type == ffmpeg::AVMediaType_AVMEDIA_TYPE_VIDEO
+      };
+
+      let best_video = || {
+        let index = ffmpeg::av_find_best_stream(
+          format_context,
+          ffmpeg::AVMediaType_AVMEDIA_TYPE_VIDEO,
+          -1,
+          -1,
+          std::ptr::null_mut(),
+          0,
+        );
+        match index {
+          ffmpeg::AVERROR_STREAM_NOT_FOUND => Err(RumpegError::VideoStreamMissing),
+          index if index < 0 => Err(RumpegError::from_code(index, "No video stream found")),
+          index => Ok(index),
+        }
+      };
+
+      let index = match selector {
+        StreamSelector::Best => best_video()?,
+        StreamSelector::Index(index) => {
+          let stream = usize::try_from(index)
+            .ok()
+            .and_then(|i| streams.get(i))
+            .ok_or(RumpegError::StreamMissing(selector))?;
+          if !is_video(&stream) {
+            return Err(RumpegError::NotAVideoStream(index));
+          }
+          index
+        }
+        StreamSelector::AttachedPicture => match streams
+          .iter()
+          .find(|stream| (***stream).disposition & ffmpeg::AV_DISPOSITION_ATTACHED_PIC as i32 != 0)
+        {
+          Some(stream) => (**stream).index,
+          None => best_video()?,
+        },
+        StreamSelector::Video(n) => streams
+          .iter()
+          .filter(is_video)
+          .nth(n)
+          .map(|stream| (**stream).index)
+          .ok_or(RumpegError::StreamMissing(selector))?,
+      };
+
+      Ok(Self {
+        ptr: *(*format_context).streams.offset(index as isize),
+        index,
+      })
+    }
+  }
+
+  pub(super) fn new_audio(format_context: *mut ffmpeg::AVFormatContext) -> RumpegResult<Self> {
     unsafe {
       let result = ffmpeg::avformat_find_stream_info(format_context, std::ptr::null_mut());
       if result < 0 {
@@ -20,14 +92,14 @@ impl AVStream {
 
       let index = ffmpeg::av_find_best_stream(
         format_context,
-        ffmpeg::AVMediaType_AVMEDIA_TYPE_VIDEO,
+        ffmpeg::AVMediaType_AVMEDIA_TYPE_AUDIO,
         -1,
         -1,
         std::ptr::null_mut(),
         0,
       );
       if index < 0 {
-        return Err(RumpegError::from_code(index, "No video stream found"));
+        return Err(RumpegError::from_code(index, "No audio stream found"));
       }
 
       Ok(Self {
@@ -55,6 +127,87 @@ impl AVStream {
     }
   }
 
+  /// Whether the stream is embedded cover art rather than a sequence of frames
+  pub fn is_attached_picture(&self) -> bool {
+    self.disposition & ffmpeg::AV_DISPOSITION_ATTACHED_PIC as i32 != 0
+  }
+
+  /// Decodes the embedded cover art, which is kept as a single packet on the stream
+  /// instead of being read along with the others
+  pub fn decode_attached_picture(
+    &self,
+    codec_context: *mut ffmpeg::AVCodecContext,
+  ) -> RumpegResult<AVFrame> {
+    if !self.is_attached_picture() || self.attached_pic.size <= 0 {
+      return Err(RumpegError::AttachedPictureMissing);
+    }
+
+    unsafe {
+      let result = ffmpeg::avcodec_send_packet(codec_context, &self.attached_pic);
+      if result < 0 {
+        return Err(RumpegError::from_code(
+          result,
+          "Error sending attached picture",
+        ));
+      }
+      // Drains the decoder, image decoders may hold on to the only frame otherwise
+      ffmpeg::avcodec_send_packet(codec_context, ptr::null());
+
+      let mut frame = AVFrame::empty()?;
+      match frame.receive_packet(codec_context)? {
+        true => Ok(frame),
+        false => Err(RumpegError::AttachedPictureMissing),
+      }
+    }
+  }
+
+  /// WebM stores the alpha of VP8 and VP9 as side data, which only libvpx decodes
+  pub fn has_alpha_mode(&self) -> bool {
+    unsafe {
+      let entry = ffmpeg::av_dict_get(
+        self.metadata,
+        b"alpha_mode\0".as_ptr() as *const _,
+        ptr::null(),
+        0,
+      );
+      entry
+        .as_ref()
+        .and_then(|entry| ptr_to_str(entry.value))
+        .is_some_and(|value| value == "1")
+    }
+  }
+
+  /// Decoder for the stream, preferring libvpx for VP8 and VP9 with alpha when it is available
+  pub fn decoder(&self) -> *const ffmpeg::AVCodec {
+    unsafe {
+      let codec_id = (*self.codecpar).codec_id;
+      let libvpx: &[u8] = match codec_id {
+        ffmpeg::AVCodecID_AV_CODEC_ID_VP8 => b"libvpx\0",
+        ffmpeg::AVCodecID_AV_CODEC_ID_VP9 => b"libvpx-vp9\0",
+        _ => b"\0",
+      };
+      if libvpx.len() > 1 && self.has_alpha_mode() {
+        let codec = ffmpeg::avcodec_find_decoder_by_name(libvpx.as_ptr() as *const _);
+        if !codec.is_null() {
+          return codec;
+        }
+        log!(warn@"Decoding without alpha, FFmpeg was built without libvpx");
+      }
+      ffmpeg::avcodec_find_decoder(codec_id)
+    }
+  }
+
+  /// Converts a timestamp in the stream's time base to milliseconds
+  pub fn timestamp_millis(&self, timestamp: i64) -> i64 {
+    unsafe {
+      ffmpeg::av_rescale_q(
+        timestamp,
+        self.time_base,
+        ffmpeg::AVRational { num: 1, den: 1000 },
+      )
+    }
+  }
+
   pub fn duration_millis(&self) -> i64 {
     (self.duration as f64 / self.time_base.den as f64 * 1000.) as i64
   }
@@ -93,3 +246,98 @@ impl DerefMut for AVStream {
     unsafe { &mut *self.ptr }
   }
 }
+
+/// Which stream of a container to decode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StreamSelector {
+  /// The stream FFmpeg considers the main video
+  #[default]
+  Best,
+  /// A stream by its index in the container, which must be a video stream
+  Index(i32),
+  /// The nth video stream, counting from 0
+  Video(usize),
+  /// Embedded cover art, or the best video stream when there is none
+  AttachedPicture,
+}
+
+impl FromStr for StreamSelector {
+  type Err = RumpegError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = |_| RumpegError::InvalidStreamSelector(s.to_string());
+    Ok(if s == "best" {
+      Self::Best
+    } else if s == "cover" {
+      Self::AttachedPicture
+    } else if let Some(n) = s.strip_prefix("v:") {
+      Self::Video(n.parse().map_err(invalid)?)
+    } else {
+      Self::Index(s.parse().map_err(invalid)?)
+    })
+  }
+}
+
+impl fmt::Display for StreamSelector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Best => write!(f, "best"),
+      Self::Index(index) => write!(f, "{index}"),
+      Self::Video(n) => write!(f, "v:{n}"),
+      Self::AttachedPicture => write!(f, "cover"),
+    }
+  }
+}
+
+/// Summary of one stream of a container, whichever its media type
+#[derive(Debug, Clone)]
+pub struct StreamInfo<'a> {
+  pub index: i32,
+  pub media_type: &'a str,
+  pub codec_name: &'a str,
+  pub language: Option<&'a str>,
+  pub title: Option<&'a str>,
+  pub disposition: Vec<&'a str>,
+}
+
+impl<'a> StreamInfo<'a> {
+  pub(super) fn new(stream: *const ffmpeg::AVStream) -> Self {
+    unsafe {
+      let codecpar = (*stream).codecpar;
+      let metadata = |key: &[u8]| {
+        let entry =
+          ffmpeg::av_dict_get((*stream).metadata, key.as_ptr() as *const _, ptr::null(), 0);
+        entry.as_ref().and_then(|entry| ptr_to_str(entry.value))
+      };
+
+      // Each set bit is a separate flag, named individually
+      let disposition = (0..i32::BITS)
+        .map(|bit| (*stream).disposition & (1 << bit))
+        .filter(|flag| *flag != 0)
+        .filter_map(|flag| ptr_to_str(ffmpeg::av_disposition_to_string(flag)))
+        .collect();
+
+      Self {
+        index: (*stream).index,
+        media_type: ptr_to_str(ffmpeg::av_get_media_type_string((*codecpar).codec_type))
+          .unwrap_or("unknown"),
+        codec_name: ptr_to_str(ffmpeg::avcodec_get_name((*codecpar).codec_id)).unwrap_or("N/A"),
+        language: metadata(b"language\0"),
+        title: metadata(b"title\0"),
+        disposition,
+      }
+    }
+  }
+}
+
+impl<'a> ToJson for StreamInfo<'a> {
+  fn write_json(&self, out: &mut String) {
+    JsonObject::new()
+      .field("index", &self.index)
+      .field("type", self.media_type)
+      .field("codec", self.codec_name)
+      .field("language", &self.language)
+      .field("title", &self.title)
+      .field("disposition", &self.disposition)
+      .write_json(out)
+  }
+}
@@ -1,17 +1,25 @@
 mod avcodec;
+mod avdictionary;
+mod avencoder;
 mod avformat;
 mod avframe;
 mod avpacket;
 mod avpixel;
+mod avsample;
 mod avstream;
+mod swr;
 mod sws;
 
 pub use avcodec::*;
+pub use avdictionary::*;
+pub use avencoder::*;
 pub use avformat::*;
 pub use avframe::*;
 pub use avpacket::*;
 pub use avpixel::*;
+pub use avsample::*;
 pub use avstream::*;
+pub use swr::*;
 pub use sws::*;
 
 use crate::{ffmpeg, math::MathError, webp::WebPError};
@@ -23,6 +31,8 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum RumpegError {
+  #[error("Stream has no attached picture")]
+  AttachedPictureMissing,
   #[error("Could not allocate AVCodecContext")]
   AVCodecContextAllocFail,
   #[error("{0}: AVError - {2} (Code {1})")]
@@ -33,16 +43,28 @@ pub enum RumpegError {
   CStringCreation(#[from] NulError),
   #[error("No decoder found")]
   DecoderMissing,
+  #[error("No {0} encoder found")]
+  EncoderMissing(String),
   #[error(transparent)]
   Math(#[from] MathError),
   #[error("Unknown codec, could not determine pixel format (Codec ID {0})")]
   PixelFormatMissing(i32),
   #[error("Could not create SwsContext")]
   SwsContextCreation,
+  #[error("Stream {0} is not a video stream")]
+  NotAVideoStream(i32),
+  #[error("No stream matches {0}")]
+  StreamMissing(StreamSelector),
+  #[error("Invalid stream selector \"{0}\"")]
+  InvalidStreamSelector(String),
   #[error("Unknown log level")]
   UnknownLogLevel,
+  #[error("Unknown option value \"{0}\"")]
+  UnknownOption(String),
   #[error("No video format found")]
   VideoFormatMissing,
+  #[error("No video stream found")]
+  VideoStreamMissing,
   #[error(transparent)]
   WebPError(#[from] WebPError),
 }
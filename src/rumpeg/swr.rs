@@ -0,0 +1,84 @@
+use std::ptr;
+
+use super::*;
+
+use crate::ffmpeg;
+
+/// Converts decoded audio of any layout and sample format to mono `f32` samples
+#[derive(Debug)]
+pub struct SwrContext {
+  ptr: *mut ffmpeg::SwrContext,
+}
+
+impl SwrContext {
+  pub fn new(codec_context: &AVCodecContext, sample_rate: i32) -> RumpegResult<Self> {
+    unsafe {
+      let mut output_layout = ffmpeg::AVChannelLayout::default();
+      ffmpeg::av_channel_layout_default(&mut output_layout, 1);
+
+      let mut ptr = ptr::null_mut();
+      let result = ffmpeg::swr_alloc_set_opts2(
+        &mut ptr,
+        &output_layout,
+        ffmpeg::AVSampleFormat_AV_SAMPLE_FMT_FLT,
+        sample_rate,
+        &codec_context.ch_layout,
+        codec_context.sample_fmt,
+        codec_context.sample_rate,
+        0,
+        ptr::null_mut(),
+      );
+      if result < 0 {
+        ffmpeg::swr_free(&mut ptr);
+        return Err(RumpegError::from_code(
+          result,
+          "Could not create SwrContext",
+        ));
+      }
+
+      let result = ffmpeg::swr_init(ptr);
+      if result < 0 {
+        ffmpeg::swr_free(&mut ptr);
+        return Err(RumpegError::from_code(
+          result,
+          "Could not initialize SwrContext",
+        ));
+      }
+
+      Ok(Self { ptr })
+    }
+  }
+
+  /// Resamples `frame`, or flushes the samples still buffered in the resampler when `None`
+  pub fn convert(&self, frame: Option<&AVFrame>) -> RumpegResult<Vec<f32>> {
+    unsafe {
+      let (input, input_count) = match frame {
+        Some(frame) => (frame.extended_data as *mut *const u8, frame.nb_samples),
+        None => (ptr::null_mut(), 0),
+      };
+
+      let capacity = ffmpeg::swr_get_out_samples(self.ptr, input_count);
+      if capacity < 0 {
+        return Err(RumpegError::from_code(capacity, "Could not resample audio"));
+      }
+
+      let mut samples = vec![0_f32; capacity as usize];
+      let mut output = samples.as_mut_ptr() as *mut u8;
+      let count = ffmpeg::swr_convert(self.ptr, &mut output, capacity, input, input_count);
+      if count < 0 {
+        return Err(RumpegError::from_code(count, "Could not resample audio"));
+      }
+
+      samples.truncate(count as usize);
+      Ok(samples)
+    }
+  }
+}
+
+impl Drop for SwrContext {
+  fn drop(&mut self) {
+    unsafe {
+      ffmpeg::swr_free(&mut self.ptr);
+    }
+  }
+}
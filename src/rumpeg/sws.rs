@@ -22,19 +22,32 @@ impl SwsContext {
     })
   }
 
+  /// Converts frames to `format`, keeping their size
+  pub fn with_format(
+    input: SwsFrameProperties,
+    format: ffmpeg::AVPixelFormat,
+  ) -> RumpegResult<Self> {
+    let output = SwsFrameProperties { format, ..input };
+    Ok(Self {
+      input,
+      output,
+      ptr: Self::get_context_ptr(input, output)?,
+    })
+  }
+
   pub fn width(&self) -> i32 {
     self.output.width
   }
 
+  pub fn format(&self) -> ffmpeg::AVPixelFormat {
+    self.output.format
+  }
+
   pub fn height(&self) -> i32 {
     self.output.height
   }
 
-  pub fn transform(
-    &self,
-    input: &mut AVFrame,
-    transform: Option<Matrix3x3>,
-  ) -> RumpegResult<AVFrame> {
+  pub fn transform(&self, input: &AVFrame, transform: Option<Matrix3x3>) -> RumpegResult<AVFrame> {
     unsafe {
       let mut output = AVFrame::new(self.output.format, self.output.width, self.output.height)?;
 
@@ -122,11 +135,16 @@ pub struct SwsFrameProperties {
 }
 
 impl SwsFrameProperties {
+  /// YUV 4:2:0 at the given size, with an alpha plane if the input has one
   pub fn output(&self, width: i32, height: i32) -> Self {
     let mut output = Self {
       width,
       height,
-      format: ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P,
+      format: if self.format.has_alpha() {
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVA420P
+      } else {
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P
+      },
     };
     output.copy_aspect_ratio(*self);
     output
@@ -1,12 +1,18 @@
 use crate::ascii::Color;
 use crate::ascii::RESET;
 use crate::ffmpeg;
+use crate::image::{EncodedImage, ImageOptions};
+use crate::json::{JsonObject, ToJson};
 use crate::math;
 use crate::rumpeg::*;
+use crate::webp::{WebPAnimEncoder, WebPError, WebPImage, WebPOptions};
 use std::fmt;
 use thiserror::Error;
 
 const MAX_FILM_WIDTH: i32 = 8;
+/// Upper bound on the frames of an animated preview, which keeps its size in check
+pub const MAX_PREVIEW_FRAMES: usize = 120;
+const DEFAULT_PREVIEW_FRAMES: usize = 48;
 
 #[derive(Debug)]
 pub struct Video<'a> {
@@ -22,20 +28,62 @@ pub struct Video<'a> {
   sws_context: SwsContext,
 }
 
+/// Tiles of a video laid out in rows, along with the time each tile was taken at
+#[derive(Debug)]
+pub struct FilmStrip {
+  pub frame: AVFrame,
+  pub timestamps_ms: Vec<i64>,
+}
+
 #[derive(Error, Debug)]
 pub enum VideoError {
   #[error(transparent)]
   Rumpeg(#[from] RumpegError),
   #[error("At least 1 frame is needed to create a film strip, found {0}")]
   NoFramesInFilmStrip(i32),
+  #[error("No frames were decoded for the animated preview")]
+  NoFramesInPreview,
+  #[error(transparent)]
+  WebP(#[from] WebPError),
 }
 
 type VideoResult<T = ()> = Result<T, VideoError>;
 
+/// Everything that is fixed once a video is opened
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VideoOptions {
+  pub stream: StreamSelector,
+  pub decoder: DecoderOptions,
+  /// Output size, a side left at 0 keeps the aspect ratio
+  pub width: i32,
+  pub height: i32,
+}
+
+/// Playback of an animated preview
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewOptions {
+  /// Frames shown per second, 0 keeps the pace of the video
+  pub fps: f64,
+  /// Times the animation plays, 0 loops forever
+  pub loop_count: i32,
+  /// Frames past this are left out, at most [`MAX_PREVIEW_FRAMES`]
+  pub max_frames: usize,
+}
+
+impl Default for PreviewOptions {
+  fn default() -> Self {
+    Self {
+      fps: 0.,
+      loop_count: 0,
+      max_frames: DEFAULT_PREVIEW_FRAMES,
+    }
+  }
+}
+
 impl<'a> Video<'a> {
-  pub fn open(filepath: &'a str, w: i32, h: i32) -> VideoResult<Video> {
-    let format_context = AVFormatContext::new(filepath)?;
-    let codec_context = AVCodecContext::new(format_context.stream.codecpar)?;
+  pub fn open(filepath: &str, options: VideoOptions) -> VideoResult<Self> {
+    let format_context = AVFormatContext::new(filepath, options.stream)?;
+    let codec_context = AVCodecContext::new(&format_context.stream, &options.decoder)?;
     let iformat = AVInputFormat::new(format_context.iformat);
     let display_matrix = format_context.stream.display_matrix();
 
@@ -46,19 +94,27 @@ impl<'a> Video<'a> {
       height: codec_context.height,
       mime_type: iformat.mime_type,
       width: codec_context.width,
-      sws_context: SwsContext::new(SwsFrameProperties::from(&codec_context), w, h)?,
+      sws_context: SwsContext::new(
+        SwsFrameProperties::from(&codec_context),
+        options.width,
+        options.height,
+      )?,
       codec_context,
       display_matrix,
       format_context,
     })
   }
 
-  pub fn frame_to_webp(&self, frame: &mut AVFrame) -> VideoResult<&[u8]> {
+  pub fn frame_to_image(
+    &self,
+    frame: &AVFrame,
+    options: &ImageOptions,
+  ) -> VideoResult<EncodedImage> {
     Ok(
       self
         .sws_context
         .transform(frame, self.display_matrix)?
-        .encode_as_webp()?,
+        .encode_as(options)?,
     )
   }
 
@@ -67,7 +123,8 @@ impl<'a> Video<'a> {
     start: SeekPosition,
     end: SeekPosition,
     step: SeekPosition,
-  ) -> VideoResult<AVFrame> {
+    mode: SeekMode,
+  ) -> VideoResult<FilmStrip> {
     let tile_count = {
       let start = self.format_context.stream.as_time_base(start);
       let end = self.format_context.stream.as_time_base(end);
@@ -92,16 +149,22 @@ impl<'a> Video<'a> {
     }
 
     let mut film_strip = AVFrame::new(
-      ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P,
+      self.sws_context.format(),
       tile_w * tile_cols,
       tile_h * tile_rows,
     )?;
     film_strip.data_mut(0).fill(0);
     film_strip.data_mut(1).fill(128);
     film_strip.data_mut(2).fill(128);
+    // Leaves the space without tiles transparent
+    if film_strip.plane_count() > 3 {
+      film_strip.data_mut(3).fill(0);
+    }
 
-    for (thumb_pos, mut frame) in self.frames(start, end, step)?.enumerate() {
-      frame = self.sws_context.transform(&mut frame, None)?; // Rotating the final film frame performs better
+    let mut timestamps_ms = Vec::with_capacity(tile_count as usize);
+    for (thumb_pos, mut frame) in self.frames(start, end, step, mode)?.enumerate() {
+      timestamps_ms.push(self.timestamp_millis(&frame));
+      frame = self.sws_context.transform(&frame, None)?; // Rotating the final film frame performs better
 
       let mut tile_x = thumb_pos as i32 % MAX_FILM_WIDTH;
       let mut tile_y = thumb_pos as i32 / MAX_FILM_WIDTH;
@@ -110,12 +173,13 @@ impl<'a> Video<'a> {
         std::mem::swap(&mut tile_x, &mut tile_y);
       }
 
-      for plane in 0..3 {
+      for plane in 0..film_strip.plane_count() {
         let frame_stride = frame.linesize[plane];
-        let frame_height = if plane == 0 {
-          frame.plane_height(0) as f32
-        } else {
+        // Only the chroma planes are subsampled, alpha is full size like luma
+        let frame_height = if plane == 1 || plane == 2 {
           frame.plane_height(0) as f32 / 2.
+        } else {
+          frame.plane_height(0) as f32
         };
 
         let film_stride = film_strip.linesize[plane];
@@ -159,7 +223,81 @@ impl<'a> Video<'a> {
       film_strip.transform(matrix)?;
     }
 
-    Ok(film_strip)
+    Ok(FilmStrip {
+      frame: film_strip,
+      timestamps_ms,
+    })
+  }
+
+  /// Animates the frames between `start` and `end`. Frames are timed by their PTS, scaled so
+  /// frames `step` apart last `1 / fps` seconds
+  pub fn animated_preview(
+    &self,
+    start: SeekPosition,
+    end: SeekPosition,
+    step: SeekPosition,
+    mode: SeekMode,
+    preview: &PreviewOptions,
+    options: &WebPOptions,
+  ) -> VideoResult<WebPImage> {
+    let step_ms = {
+      let stream = &self.format_context.stream;
+      stream.timestamp_millis(stream.as_time_base(step))
+    };
+    let preview_timestamp_ms = |index: usize, pts_ms: i64| match preview.fps {
+      fps if fps > 0. && step_ms > 0 => (pts_ms as f64 * 1000. / fps / step_ms as f64) as i64,
+      // Steps shorter than a millisecond are a frame each
+      fps if fps > 0. => (index as f64 * 1000. / fps) as i64,
+      _ => pts_ms,
+    };
+
+    let mut encoder: Option<WebPAnimEncoder> = None;
+    let mut first_pts_ms = None;
+    let mut last_pts_ms = i64::MIN;
+    let mut timestamps_ms: Vec<i64> = Vec::new();
+    let max_frames = std::cmp::min(preview.max_frames, MAX_PREVIEW_FRAMES);
+
+    for frame in self.frames(start, end, step, mode)? {
+      let pts_ms = self.timestamp_millis(&frame);
+      // Seeking to keyframes can land on the same frame for several steps
+      if pts_ms <= last_pts_ms {
+        continue;
+      }
+      last_pts_ms = pts_ms;
+
+      let first_pts_ms = *first_pts_ms.get_or_insert(pts_ms);
+      let timestamp_ms = std::cmp::max(
+        preview_timestamp_ms(timestamps_ms.len(), pts_ms - first_pts_ms),
+        timestamps_ms.last().map_or(0, |last| last + 1),
+      );
+
+      let frame = self.sws_context.transform(&frame, self.display_matrix)?;
+      let encoder = match &mut encoder {
+        Some(encoder) => encoder,
+        None => encoder.insert(WebPAnimEncoder::new(
+          frame.width,
+          frame.height,
+          preview.loop_count,
+          options,
+        )?),
+      };
+      encoder.add(&frame, timestamp_ms as i32)?;
+      timestamps_ms.push(timestamp_ms);
+
+      if timestamps_ms.len() >= max_frames {
+        break;
+      }
+    }
+
+    let Some(encoder) = encoder else {
+      return Err(VideoError::NoFramesInPreview);
+    };
+    // The last frame lasts as long as the one before it
+    let end_ms = match timestamps_ms[..] {
+      [.., previous, last] => last + (last - previous),
+      _ => preview_timestamp_ms(1, step_ms),
+    };
+    Ok(encoder.finish(std::cmp::max(end_ms, 1) as i32)?)
   }
 
   pub fn frames(
@@ -167,21 +305,100 @@ impl<'a> Video<'a> {
     start: SeekPosition,
     end: SeekPosition,
     step: SeekPosition,
+    mode: SeekMode,
   ) -> VideoResult<AVFrameIter> {
     self.seek(start)?;
     Ok(
       self
         .format_context
-        .frames(self.codec_context.as_ptr(), start, end, step),
+        .frames(self.codec_context.as_ptr(), start, end, step, mode),
     )
   }
 
+  /// Presentation time of a decoded frame, which in keyframe mode can lie before the
+  /// requested position
+  pub fn timestamp_millis(&self, frame: &AVFrame) -> i64 {
+    self.format_context.stream.timestamp_millis(frame.pts)
+  }
+
+  pub fn is_attached_picture(&self) -> bool {
+    self.format_context.stream.is_attached_picture()
+  }
+
+  /// Decodes the cover art the video was opened on, see [`StreamSelector::AttachedPicture`]
+  pub fn attached_picture(&self) -> VideoResult<AVFrame> {
+    self.codec_context.flush();
+    Ok(
+      self
+        .format_context
+        .stream
+        .decode_attached_picture(self.codec_context.as_ptr())?,
+    )
+  }
+
+  pub fn codec_name(&self) -> &'a str {
+    unsafe { ptr_to_str(ffmpeg::avcodec_get_name(self.codec_context.codec_id)).unwrap_or("N/A") }
+  }
+
   fn seek(&self, position: SeekPosition) -> RumpegResult {
     self.codec_context.flush();
     self.format_context.seek(position)
   }
 }
 
+impl<'a> ToJson for Video<'a> {
+  fn write_json(&self, out: &mut String) {
+    let bit_rate = match self.codec_context.bit_rate {
+      0 => self.format_context.bit_rate,
+      n => n,
+    };
+
+    JsonObject::new()
+      .field("file_name", &ptr_to_str(self.format_context.url))
+      .field("container", self.format_name)
+      .field("extensions", self.extensions)
+      .field("mime_type", self.mime_type)
+      .field("codec", self.codec_name())
+      .field("pixel_format", self.codec_context.format.av_pix_fmt_name())
+      .field("has_alpha", &self.codec_context.format.has_alpha())
+      .field("width", &self.codec_context.width)
+      .field("height", &self.codec_context.height)
+      .field("output_width", &self.sws_context.width())
+      .field("output_height", &self.sws_context.height())
+      .field(
+        "sample_aspect_ratio",
+        &self.codec_context.sample_aspect_ratio,
+      )
+      .field(
+        "rotation",
+        &self.display_matrix.map(|m| m.rotation()).unwrap_or(0.),
+      )
+      .field("display_matrix", &self.display_matrix.map(|m| m.to_vec()))
+      .field("time_base", &self.format_context.stream.time_base)
+      .field("duration_ms", &self.duration_ms)
+      .field("stream_duration", &self.format_context.stream.duration)
+      .field(
+        "container_duration_ms",
+        // Containers that don't store a duration leave it unset
+        &(self.format_context.duration != ffmpeg::AV_NOPTS_VALUE)
+          .then(|| self.format_context.duration / (ffmpeg::AV_TIME_BASE as i64 / 1000)),
+      )
+      .field("frame_rate", &self.codec_context.framerate)
+      .field("avg_frame_rate", &self.format_context.stream.avg_frame_rate)
+      .field("base_frame_rate", &self.format_context.stream.r_frame_rate)
+      .field("gop_size", &self.codec_context.gop_size)
+      .field("bit_rate", &bit_rate)
+      .field("stream_index", &self.format_context.stream.index)
+      .field("attached_picture", &self.is_attached_picture())
+      .field("streams", &self.format_context.streams())
+      .write_json(out)
+  }
+}
+
+// The FFmpeg contexts are exclusively owned by the video and only touched through it,
+// so it can move between threads as long as it is not used from two at once
+unsafe impl<'a> Send for Video<'a> {}
+
 impl<'a> fmt::Display for Video<'a> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
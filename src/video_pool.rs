@@ -0,0 +1,130 @@
+use crate::video::{Video, VideoError, VideoOptions};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, io, thread};
+
+/// A pooled video, locked for the whole decode so seeks on one handle never interleave
+pub type VideoHandle = Arc<Mutex<Video<'static>>>;
+
+/// Bounded set of open videos, reused across requests to skip probing the
+/// container and setting up the decoder every time
+///
+/// Handles are keyed by path and the options they were opened with.
+/// A handle is reopened once its file's modification time changes and closed
+/// after sitting idle for longer than the idle timeout, either on the next lookup
+/// or by the sweeper thread
+#[derive(Debug)]
+pub struct VideoPool {
+  max_handles: usize,
+  idle_timeout: Duration,
+  entries: Mutex<Vec<PoolEntry>>,
+}
+
+#[derive(Debug)]
+struct PoolEntry {
+  path: String,
+  options: VideoOptions,
+  modified: Option<SystemTime>,
+  last_used: Instant,
+  video: VideoHandle,
+}
+
+impl PoolEntry {
+  fn matches(&self, filepath: &str, options: VideoOptions) -> bool {
+    self.path == filepath && self.options == options
+  }
+
+  fn in_use(&self) -> bool {
+    Arc::strong_count(&self.video) > 1
+  }
+}
+
+impl VideoPool {
+  pub fn new(max_handles: usize, idle_timeout: Duration) -> Self {
+    Self {
+      max_handles,
+      idle_timeout,
+      entries: Mutex::new(Vec::with_capacity(max_handles)),
+    }
+  }
+
+  /// Returns the pooled handle for `filepath` opened with `options`, opening it if needed
+  pub fn get(&self, filepath: &str, options: VideoOptions) -> Result<VideoHandle, VideoError> {
+    let modified = fs::metadata(filepath).and_then(|m| m.modified()).ok();
+    let now = Instant::now();
+
+    {
+      let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+      self.evict_idle(&mut entries, now);
+
+      if let Some(pos) = entries
+        .iter()
+        .position(|entry| entry.matches(filepath, options))
+      {
+        // Callers still holding the stale handle finish with it, later ones get a fresh one
+        if entries[pos].modified != modified {
+          entries.swap_remove(pos);
+        } else {
+          entries[pos].last_used = now;
+          return Ok(entries[pos].video.clone());
+        }
+      }
+    }
+
+    // Opened outside the lock so a slow probe doesn't block requests for other files
+    let video = Arc::new(Mutex::new(Video::open(filepath, options)?));
+
+    let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(entry) = entries
+      .iter()
+      .find(|entry| entry.matches(filepath, options) && entry.modified == modified)
+    {
+      // Another request opened the same video in the meantime
+      return Ok(entry.video.clone());
+    }
+
+    if entries.len() >= self.max_handles {
+      let Some(pos) = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.in_use())
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(pos, _)| pos)
+      else {
+        // Every handle is busy, serve this one without pooling it
+        return Ok(video);
+      };
+      entries.swap_remove(pos);
+    }
+
+    entries.push(PoolEntry {
+      path: filepath.to_string(),
+      options,
+      modified,
+      last_used: now,
+      video: video.clone(),
+    });
+
+    Ok(video)
+  }
+
+  /// Closes idle handles every idle timeout, so they don't stay open until the next lookup
+  pub fn spawn_sweeper(&'static self) -> io::Result<()> {
+    let interval = self.idle_timeout.max(Duration::from_secs(1));
+    thread::Builder::new()
+      .name("Video pool sweeper".to_string())
+      .spawn(move || loop {
+        thread::sleep(interval);
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        self.evict_idle(&mut entries, Instant::now());
+      })?;
+    Ok(())
+  }
+
+  fn evict_idle(&self, entries: &mut Vec<PoolEntry>, now: Instant) {
+    entries.retain(|entry| {
+      !entry.video.is_poisoned()
+        && (entry.in_use() || now.duration_since(entry.last_used) < self.idle_timeout)
+    });
+  }
+}
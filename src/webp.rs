@@ -38,6 +38,26 @@ mod libwebp {
     WebPPictureInitInternal(picture, WEBP_ENCODER_ABI_VERSION as i32)
   }
 
+  /// Should always be called, to initialize a fresh WebPAnimEncoderOptions
+  /// structure before modification. Returns false in case of version mismatch.
+  /// WebPAnimEncoderOptionsInit() must have succeeded before using the
+  /// 'enc_options' object.
+  #[inline]
+  pub unsafe fn WebPAnimEncoderOptionsInit(enc_options: *mut WebPAnimEncoderOptions) -> i32 {
+    WebPAnimEncoderOptionsInitInternal(enc_options, WEBP_MUX_ABI_VERSION as i32)
+  }
+
+  /// Creates and initializes a WebPAnimEncoder object. Returns NULL in case of
+  /// error, the object should be deleted using WebPAnimEncoderDelete().
+  #[inline]
+  pub unsafe fn WebPAnimEncoderNew(
+    width: i32,
+    height: i32,
+    enc_options: *const WebPAnimEncoderOptions,
+  ) -> *mut WebPAnimEncoder {
+    WebPAnimEncoderNewInternal(width, height, enc_options, WEBP_MUX_ABI_VERSION as i32)
+  }
+
   pub fn webp_error<'a>(error_code: i32) -> &'a str {
     match error_code {
       WebPEncodingError_VP8_ENC_OK => "Everything ok [VP8_ENC_OK]",
@@ -78,8 +98,14 @@ mod libwebp {
 }
 
 use crate::ffmpeg;
+use crate::image::{EncodedImage, ImageEncoder};
 use crate::rumpeg::AVFrame;
 use crate::rumpeg::AVPixelFormatMethods;
+use crate::rumpeg::RumpegResult;
+use std::ffi::CStr;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -90,6 +116,12 @@ pub enum WebPError {
   WebPConfigInit,
   #[error("Format \"{0}\" is not supported")]
   FormatNotSupported(String),
+  #[error("Webp encoding options are out of range")]
+  InvalidConfig,
+  #[error("Unknown Webp preset \"{0}\"")]
+  UnknownPreset(String),
+  #[error("Webp animation encoding failed: {0}")]
+  Animation(String),
 }
 
 impl WebPError {
@@ -104,20 +136,133 @@ impl WebPError {
 
 type WebPResult<T = ()> = Result<T, WebPError>;
 
+/// Starting points for the encoder settings, tuned for kinds of content
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WebPPreset {
+  #[default]
+  Default,
+  /// Digital pictures, like portraits and indoor shots
+  Picture,
+  /// Outdoor photographs, with natural lighting
+  Photo,
+  /// Hand or line drawings, with high contrast details
+  Drawing,
+  /// Small-sized colorful images
+  Icon,
+  /// Text-like content
+  Text,
+}
+
+impl WebPPreset {
+  fn as_raw(&self) -> libwebp::WebPPreset {
+    match self {
+      Self::Default => libwebp::WebPPreset_WEBP_PRESET_DEFAULT,
+      Self::Picture => libwebp::WebPPreset_WEBP_PRESET_PICTURE,
+      Self::Photo => libwebp::WebPPreset_WEBP_PRESET_PHOTO,
+      Self::Drawing => libwebp::WebPPreset_WEBP_PRESET_DRAWING,
+      Self::Icon => libwebp::WebPPreset_WEBP_PRESET_ICON,
+      Self::Text => libwebp::WebPPreset_WEBP_PRESET_TEXT,
+    }
+  }
+}
+
+impl FromStr for WebPPreset {
+  type Err = WebPError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "default" => Ok(Self::Default),
+      "picture" => Ok(Self::Picture),
+      "photo" => Ok(Self::Photo),
+      "drawing" => Ok(Self::Drawing),
+      "icon" => Ok(Self::Icon),
+      "text" => Ok(Self::Text),
+      s => Err(WebPError::UnknownPreset(s.to_string())),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebPOptions {
+  /// Between 0 and 100, for lossless it is the effort spent on compression instead
+  pub quality: f32,
+  pub lossless: bool,
+  /// Between 0 and 100, lower values preprocess lossless images more, 100 is off
+  pub near_lossless: i32,
+  /// Between 0 (fastest) and 6 (smallest)
+  pub method: i32,
+  pub preset: WebPPreset,
+  /// Size in bytes to aim for, 0 is off
+  pub target_size: i32,
+  /// Minimal distortion to aim for in dB, 0 is off
+  pub target_psnr: f32,
+  /// Between 0 and 100
+  pub alpha_quality: i32,
+  /// Slower but sharper RGB to YUV conversion
+  pub sharp_yuv: bool,
+  pub multithreading: bool,
+}
+
+impl Default for WebPOptions {
+  fn default() -> Self {
+    Self {
+      quality: 50.,
+      lossless: false,
+      near_lossless: 100,
+      method: 4,
+      preset: WebPPreset::Default,
+      target_size: 0,
+      target_psnr: 0.,
+      alpha_quality: 100,
+      sharp_yuv: false,
+      multithreading: false,
+    }
+  }
+}
+
+impl WebPOptions {
+  /// Settings that change the encoded image, threading only changes how fast it is made
+  pub fn cache_key(&self) -> String {
+    format!(
+      "q={} lossless={} near_lossless={} method={} preset={:?} target_size={} target_psnr={} \
+       alpha_q={} sharp_yuv={}",
+      self.quality,
+      self.lossless,
+      self.near_lossless,
+      self.method,
+      self.preset,
+      self.target_size,
+      self.target_psnr,
+      self.alpha_quality,
+      self.sharp_yuv,
+    )
+  }
+}
+
 pub struct WebPEncoder {
   pic: libwebp::WebPPicture,
   config: libwebp::WebPConfig,
 }
 
 impl WebPEncoder {
-  pub fn new(frame: &AVFrame, quality: f32) -> WebPResult<Self> {
+  pub fn new(frame: &AVFrame, options: &WebPOptions) -> WebPResult<Self> {
     unsafe {
       let mut config = libwebp::WebPConfig::default();
-      if libwebp::WebPConfigInit(&mut config) == 0 {
+      if libwebp::WebPConfigPreset(&mut config, options.preset.as_raw(), options.quality) == 0 {
         return Err(WebPError::WebPConfigInit);
       }
 
-      config.quality = quality;
+      config.lossless = options.lossless as i32;
+      config.near_lossless = options.near_lossless;
+      config.method = options.method;
+      config.target_size = options.target_size;
+      config.target_PSNR = options.target_psnr;
+      config.alpha_quality = options.alpha_quality;
+      config.use_sharp_yuv = options.sharp_yuv as i32;
+      config.thread_level = options.multithreading as i32;
+
+      if libwebp::WebPValidateConfig(&config) == 0 {
+        return Err(WebPError::InvalidConfig);
+      }
 
       let mut pic = libwebp::WebPPicture::default();
       if libwebp::WebPPictureInit(&mut pic) == 0 {
@@ -126,18 +271,32 @@ impl WebPEncoder {
 
       pic.width = frame.width;
       pic.height = frame.height;
+      let has_alpha = frame.format.has_alpha();
+      // RGB is converted to YUV on import otherwise, before sharp YUV or lossless can apply
+      if matches!(
+        frame.format,
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_RGB24 | ffmpeg::AVPixelFormat_AV_PIX_FMT_RGBA
+      ) {
+        pic.use_argb = (options.lossless || options.sharp_yuv) as i32;
+      }
 
       match frame.format {
-        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P => {
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_YUV420P | ffmpeg::AVPixelFormat_AV_PIX_FMT_YUVA420P => {
+          if has_alpha {
+            pic.colorspace = libwebp::WebPEncCSP_WEBP_YUV420A;
+          }
           if libwebp::WebPPictureAlloc(&mut pic) == 0 {
             libwebp::WebPPictureFree(&mut pic);
             return Err(WebPError::from_code(pic.error_code));
           }
-          pic.y = frame.data[0];
-          pic.y_stride = frame.linesize[0];
-          pic.u = frame.data[1];
-          pic.v = frame.data[2];
-          pic.uv_stride = frame.linesize[1];
+          // Copied into the picture's own buffers, the frame may be gone before encoding
+          let (chroma_width, chroma_height) = ((pic.width + 1) / 2, (pic.height + 1) / 2);
+          copy_plane(frame, 0, pic.y, pic.y_stride, pic.width, pic.height);
+          copy_plane(frame, 1, pic.u, pic.uv_stride, chroma_width, chroma_height);
+          copy_plane(frame, 2, pic.v, pic.uv_stride, chroma_width, chroma_height);
+          if has_alpha {
+            copy_plane(frame, 3, pic.a, pic.a_stride, pic.width, pic.height);
+          }
         }
         ffmpeg::AVPixelFormat_AV_PIX_FMT_RGB24
           if libwebp::WebPPictureImportRGB(&mut pic, frame.data[0], frame.linesize[0]) == 0 =>
@@ -145,6 +304,13 @@ impl WebPEncoder {
           libwebp::WebPPictureFree(&mut pic);
           return Err(WebPError::from_code(pic.error_code));
         }
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_RGBA
+          if libwebp::WebPPictureImportRGBA(&mut pic, frame.data[0], frame.linesize[0]) == 0 =>
+        {
+          libwebp::WebPPictureFree(&mut pic);
+          return Err(WebPError::from_code(pic.error_code));
+        }
+        ffmpeg::AVPixelFormat_AV_PIX_FMT_RGB24 | ffmpeg::AVPixelFormat_AV_PIX_FMT_RGBA => {}
         format => return Err(WebPError::from_format(format)),
       }
 
@@ -152,19 +318,159 @@ impl WebPEncoder {
     }
   }
 
-  pub fn encode<'a>(&mut self) -> WebPResult<&'a [u8]> {
+  pub fn encode(&mut self) -> WebPResult<WebPImage> {
     unsafe {
-      let mut writer = libwebp::WebPMemoryWriter::default();
-      libwebp::WebPMemoryWriterInit(&mut writer);
+      // Owned right away, so the buffer is also released when encoding fails
+      let mut image = WebPImage {
+        writer: libwebp::WebPMemoryWriter::default(),
+      };
+      libwebp::WebPMemoryWriterInit(&mut image.writer);
       self.pic.writer = Some(libwebp::WebPMemoryWrite);
-      self.pic.custom_ptr = &mut writer as *mut _ as *mut std::ffi::c_void;
+      self.pic.custom_ptr = &mut image.writer as *mut _ as *mut std::ffi::c_void;
       let encode_result = libwebp::WebPEncode(&self.config, &mut self.pic);
+      self.pic.custom_ptr = std::ptr::null_mut();
 
       if encode_result == 0 {
         return Err(WebPError::from_code(self.pic.error_code));
       }
 
-      Ok(std::slice::from_raw_parts(writer.mem, writer.size))
+      Ok(image)
+    }
+  }
+}
+
+impl ImageEncoder for WebPOptions {
+  fn encode(&self, frame: &AVFrame) -> RumpegResult<EncodedImage> {
+    Ok(EncodedImage::WebP(WebPEncoder::new(frame, self)?.encode()?))
+  }
+}
+
+/// Collects frames into an animated WebP, each one is shown until the next one starts
+pub struct WebPAnimEncoder {
+  encoder: *mut libwebp::WebPAnimEncoder,
+  options: WebPOptions,
+}
+
+impl WebPAnimEncoder {
+  /// All frames have to be `width` by `height`, a `loop_count` of 0 loops forever
+  pub fn new(width: i32, height: i32, loop_count: i32, options: &WebPOptions) -> WebPResult<Self> {
+    unsafe {
+      let mut anim_options = libwebp::WebPAnimEncoderOptions::default();
+      if libwebp::WebPAnimEncoderOptionsInit(&mut anim_options) == 0 {
+        return Err(WebPError::WebPConfigInit);
+      }
+      anim_options.anim_params.loop_count = loop_count;
+
+      let encoder = libwebp::WebPAnimEncoderNew(width, height, &anim_options);
+      if encoder.is_null() {
+        return Err(WebPError::Animation(format!(
+          "Could not create a {width}x{height} animation"
+        )));
+      }
+
+      Ok(Self {
+        encoder,
+        options: *options,
+      })
+    }
+  }
+
+  /// Adds a frame starting at `timestamp_ms`, which may not lie before the previous one
+  pub fn add(&mut self, frame: &AVFrame, timestamp_ms: i32) -> WebPResult {
+    let mut frame_encoder = WebPEncoder::new(frame, &self.options)?;
+    unsafe {
+      if libwebp::WebPAnimEncoderAdd(
+        self.encoder,
+        &mut frame_encoder.pic,
+        timestamp_ms,
+        &frame_encoder.config,
+      ) == 0
+      {
+        return Err(self.error());
+      }
+    }
+    Ok(())
+  }
+
+  /// Assembles the animation, showing the last frame until `end_timestamp_ms`
+  pub fn finish(self, end_timestamp_ms: i32) -> WebPResult<WebPImage> {
+    unsafe {
+      if libwebp::WebPAnimEncoderAdd(
+        self.encoder,
+        std::ptr::null_mut(),
+        end_timestamp_ms,
+        std::ptr::null(),
+      ) == 0
+      {
+        return Err(self.error());
+      }
+
+      let mut data = libwebp::WebPData::default();
+      if libwebp::WebPAnimEncoderAssemble(self.encoder, &mut data) == 0 {
+        return Err(self.error());
+      }
+
+      // Allocated by libwebp just like the memory writer's buffer, so it is freed the same way
+      Ok(WebPImage {
+        writer: libwebp::WebPMemoryWriter {
+          mem: data.bytes as *mut u8,
+          size: data.size,
+          max_size: data.size,
+          ..Default::default()
+        },
+      })
+    }
+  }
+
+  fn error(&self) -> WebPError {
+    let message = unsafe { CStr::from_ptr(libwebp::WebPAnimEncoderGetError(self.encoder)) };
+    WebPError::Animation(message.to_string_lossy().to_string())
+  }
+}
+
+impl Drop for WebPAnimEncoder {
+  fn drop(&mut self) {
+    unsafe {
+      libwebp::WebPAnimEncoderDelete(self.encoder);
+    }
+  }
+}
+
+/// An encoded WebP image, its buffer is owned by libwebp and released on drop
+pub struct WebPImage {
+  writer: libwebp::WebPMemoryWriter,
+}
+
+// The buffer is exclusively owned and never shared with libwebp after encoding
+unsafe impl Send for WebPImage {}
+
+impl Deref for WebPImage {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    if self.writer.mem.is_null() {
+      return &[];
+    }
+    unsafe { std::slice::from_raw_parts(self.writer.mem, self.writer.size) }
+  }
+}
+
+impl AsRef<[u8]> for WebPImage {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+impl fmt::Debug for WebPImage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "WebPImage {{ size: {} }}", self.writer.size)
+  }
+}
+
+impl Drop for WebPImage {
+  fn drop(&mut self) {
+    unsafe {
+      libwebp::WebPMemoryWriterClear(&mut self.writer);
     }
   }
 }
@@ -177,6 +483,24 @@ impl Drop for WebPEncoder {
   }
 }
 
+/// Copies `rows` rows of `width` bytes from a plane of `frame` into a picture buffer
+unsafe fn copy_plane(
+  frame: &AVFrame,
+  plane: usize,
+  dest: *mut u8,
+  dest_stride: i32,
+  width: i32,
+  rows: i32,
+) {
+  for row in 0..rows as isize {
+    std::ptr::copy_nonoverlapping(
+      frame.data[plane].offset(row * frame.linesize[plane] as isize),
+      dest.offset(row * dest_stride as isize),
+      width as usize,
+    );
+  }
+}
+
 pub fn version() -> String {
   let version = unsafe { libwebp::WebPGetEncoderVersion() };
   let major = ((version >> 16) & 0xFF) as u8;